@@ -0,0 +1,14 @@
+//! Records the units rustc builds and links against for `cargo sweep`, when set as `RUSTC_WRAPPER`.
+
+use std::{env, process};
+
+fn main() {
+    match cargo_sweep::rustc_wrapper(env::args_os().skip(1).collect()) {
+        // Killed by a signal otherwise.
+        Ok(status) => process::exit(status.code().unwrap_or(101)),
+        Err(e) => {
+            eprintln!("error: {e:?}");
+            process::exit(101);
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use anyhow::Error;
+use log::debug;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::disk_usage::disk_usage;
+use crate::fingerprint::{all_fingerprints, hash_u64, last_used_time};
+use crate::layout::lookup_all_profiles;
+use crate::plan::{PlanEntry, Reason, SweepPlan};
+
+/// The build directory of a single workspace in a shared build directory root, e.g. `~/.cargo/build/b4/e1ed5001f01777`
+/// for `build-dir = "{cargo-cache-home}/build/{workspace-path-hash}"`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkspaceBuildDir {
+    pub path: PathBuf,
+    /// The hash of the path to the `Cargo.toml` of the workspace, split by Cargo into the two directory names.
+    pub hash: String,
+    /// The known workspace the build directory belongs to, if any.
+    pub workspace: Option<PathBuf>,
+    /// Time elapsed since any unit in the build directory was last used.
+    pub last_used: Duration,
+}
+
+/// The hash Cargo uses for `{workspace-path-hash}`, from the path to the `Cargo.toml` of the workspace.
+pub fn workspace_path_hash(workspace: &Path) -> String {
+    hash_u64(&workspace.join("Cargo.toml"))
+        .to_le_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn is_hex(name: &str, len: usize) -> bool {
+    name.len() == len && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Loads every workspace build directory in a shared build directory root, laid out as `{root}/ab/cdef0123456789`,
+/// and maps it back to the one of `known_workspaces` it belongs to.
+///
+/// Cargo doesn't record the workspace in the build directory, so there is no telling where any other directory came from.
+pub fn load_workspace_build_dirs(
+    root: &Path,
+    known_workspaces: &[PathBuf],
+) -> Result<Vec<WorkspaceBuildDir>, Error> {
+    let known: Vec<_> = known_workspaces
+        .iter()
+        .map(|workspace| (workspace_path_hash(workspace), workspace))
+        .collect();
+    let mut dirs = vec![];
+    for entry in fs::read_dir(root)? {
+        let prefix_dir = entry?.path();
+        let prefix = prefix_dir
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        if !prefix_dir.is_dir() || !is_hex(&prefix, 2) {
+            continue;
+        }
+        for entry in fs::read_dir(&prefix_dir)? {
+            let path = entry?.path();
+            let rest = path.file_name().unwrap().to_string_lossy();
+            if !path.is_dir() || !is_hex(&rest, 14) {
+                continue;
+            }
+            let hash = format!("{prefix}{rest}");
+            let workspace = known
+                .iter()
+                .find(|(known, _)| *known == hash)
+                .map(|(_, workspace)| workspace.to_path_buf());
+            debug!("Workspace of {:?}: {:?}", path, workspace);
+            dirs.push(WorkspaceBuildDir {
+                last_used: last_used_in_build_dir(&path)?,
+                path,
+                hash,
+                workspace,
+            });
+        }
+    }
+    dirs.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(dirs)
+}
+
+/// The most recent use of any unit in a build directory, or of the directory itself if it has no units.
+fn last_used_in_build_dir(dir: &Path) -> Result<Duration, Error> {
+    let mut last_used = None;
+    for profile in lookup_all_profiles(dir) {
+        for (fingerprint, _) in all_fingerprints(&profile)? {
+            let used = last_used_time(&fingerprint)?;
+            last_used = Some(last_used.map_or(used, |last: Duration| last.min(used)));
+        }
+    }
+    match last_used {
+        Some(last_used) => Ok(last_used),
+        None => last_used_time(dir),
+    }
+}
+
+/// Plans the removal of the workspace build directories in a shared build directory root that were
+/// not used within `keep_duration`, if given.
+///
+/// With `all_known`, `known_workspaces` are taken to be every workspace there is, so the directories
+/// that don't belong to any of them are left over from workspaces that were moved or deleted, and removed as well.
+pub fn plan_sweep_build_root(
+    root: &Path,
+    known_workspaces: &[PathBuf],
+    all_known: bool,
+    keep_duration: Option<Duration>,
+) -> Result<SweepPlan, Error> {
+    let mut plan = SweepPlan::new();
+    for dir in load_workspace_build_dirs(root, known_workspaces)? {
+        let reason = if all_known && dir.workspace.is_none() {
+            Reason::WorkspaceGone
+        } else if let Some(keep_duration) = keep_duration.filter(|keep| dir.last_used >= *keep) {
+            Reason::OlderThan(keep_duration)
+        } else {
+            continue;
+        };
+        let size = disk_usage(&dir.path);
+        plan.push(PlanEntry::new(dir.path, dir.hash, size, reason));
+    }
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_workspace_path_hash() {
+        // As reported by `cargo metadata` for a workspace in `/tmp/bd/sample-project`.
+        assert_eq!(
+            workspace_path_hash(Path::new("/tmp/bd/sample-project")),
+            "b4e1ed5001f01777"
+        );
+    }
+}
@@ -1,6 +1,10 @@
-use anyhow::anyhow;
-use clap::{ArgGroup, Parser};
-use std::path::PathBuf;
+use anyhow::{anyhow, bail};
+use cargo_sweep::FreeSpace;
+use clap::{Parser, ValueEnum};
+use serde_derive::Deserialize;
+use std::{ffi::OsString, path::PathBuf};
+
+use crate::config::Config;
 
 const MEGABYTE: u64 = 1024 * 1024;
 
@@ -29,51 +33,121 @@ pub enum SweepCommand {
 
 #[derive(Parser, Debug)]
 #[cfg_attr(test, derive(Default, PartialEq))]
-#[command(
-    about,
-    version,
-    group(
-        ArgGroup::new("criterion")
-            .required(true)
-            .args(["stamp", "file", "all", "time", "installed", "toolchains", "maxsize"])
-    )
-)]
+#[command(about, version, subcommand_negates_reqs = true)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Path to check
     pub path: Vec<PathBuf>,
 
     /// Dry run which will not delete any files
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub dry_run: bool,
 
     /// Load timestamp file in the given path, cleaning everything older
-    #[arg(short, long)]
+    #[arg(short, long, conflicts_with_all = ["all", "time"])]
     file: bool,
 
     #[arg(
         long,
         help = "The `recursive` flag defaults to ignoring directories \
             that start with a `.`, `.git` for example is unlikely to include a \
-            Cargo project, this flag changes it to look in them"
+            Cargo project, this flag changes it to look in them",
+        global = true
     )]
     pub hidden: bool,
 
+    /// Remove oldest artifacts until the filesystem holding the target folder has SIZE free space
+    ///
+    /// Either a size like for --maxsize or a percentage of the filesystem,
+    /// examples: --ensure-free 30GB, --ensure-free 10%
+    #[arg(long, value_name = "SIZE", conflicts_with = "quarantine")]
+    ensure_free: Option<String>,
+
+    /// Choose the units to sweep from a list in the terminal
+    ///
+    /// The units selected by the other options are selected to begin with.
+    /// Only the chosen units are removed, incremental compilation sessions are left alone.
+    #[arg(long, conflicts_with_all = ["stamp", "build_dir_root"])]
+    pub interactive: bool,
+
     /// Keep only artifacts made by Toolchains currently installed by rustup
-    #[arg(short, long)]
+    #[arg(short, long, conflicts_with = "toolchains")]
     installed: bool,
 
+    /// Keep only the N most recently used builds of every crate in each profile
+    ///
+    /// Builds of a crate with other features, dependencies or settings accumulate over time,
+    /// these are removed regardless of their age. Builds of its build script, tests
+    /// and binaries are counted separately.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    keep_latest: Option<u64>,
+
+    /// Remove artifacts of packages that no workspace member can depend on anymore
+    ///
+    /// The dependency graph of every swept workspace is resolved with `cargo metadata`, for all
+    /// features and platforms. Versions of a dependency that were replaced, e.g. by an upgrade, are
+    /// removed. Units of a package that can't be told apart by version, like the output of its
+    /// build script, are removed once the package is gone altogether. A target folder shared with
+    /// workspaces that aren't swept loses their dependencies as well.
+    #[arg(long)]
+    unreferenced: bool,
+
     /// Remove oldest artifacts from the target folder until it's smaller than MAXSIZE
     ///
     /// Unit defaults to MB, examples: --maxsize 500, --maxsize 10GB
-    #[arg(short, long, value_name = "MAXSIZE")]
+    #[arg(short, long, value_name = "MAXSIZE", conflicts_with = "quarantine")]
     maxsize: Option<String>,
 
+    /// Write what would be removed to the given file instead of removing it, see `apply`
+    #[arg(long, value_name = "FILE")]
+    pub plan_out: Option<PathBuf>,
+
+    /// Move swept artifacts into the given folder instead of removing them, see `restore` and `purge`
+    ///
+    /// The space is only freed once the quarantine is purged, so this can't be combined with
+    /// --maxsize, --total-maxsize or --ensure-free.
+    #[arg(long, value_name = "DIR", global = true)]
+    pub quarantine: Option<PathBuf>,
+
+    /// Sweep the per-workspace build directories in a shared build-dir root instead of target folders
+    ///
+    /// This is `~/.cargo/build` for `build-dir = "{cargo-cache-home}/build/{workspace-path-hash}"`.
+    /// With --time or --all, directories not used within that time are removed. With --recursive,
+    /// the given paths are searched for every workspace, and the directories that don't belong to
+    /// any of them are removed as left over from moved or deleted workspaces. The paths have to be
+    /// given explicitly then.
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = [
+            "stamp", "file", "installed", "toolchains", "unreferenced", "keep_latest", "maxsize",
+            "total_maxsize", "ensure_free"
+        ]
+    )]
+    pub build_dir_root: Option<PathBuf>,
+
+    /// Remove oldest artifacts across all projects until their combined size is smaller than MAXSIZE
+    ///
+    /// Unlike --maxsize, the size budget is shared by all target folders, which is mostly useful
+    /// with --recursive. Unit defaults to MB, examples: --total-maxsize 500, --total-maxsize 10GB
+    #[arg(long, value_name = "MAXSIZE", conflicts_with = "quarantine")]
+    total_maxsize: Option<String>,
+
     /// Apply on all projects below the given path
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub recursive: bool,
 
     /// Store timestamp file at the given path, is used by file option
-    #[arg(short, long)]
+    #[arg(
+        short,
+        long,
+        conflicts_with_all = [
+            "file", "all", "time", "installed", "toolchains", "unreferenced", "keep_latest",
+            "maxsize", "total_maxsize", "ensure_free"
+        ]
+    )]
     stamp: bool,
 
     /// Delete only artifacts older than ... days
@@ -81,47 +155,289 @@ pub struct Args {
     time: Option<u64>,
 
     /// Apply on all provided projects
-    #[arg(short, long)]
+    #[arg(short, long, conflicts_with = "time")]
     all: bool,
 
     /// Toolchains currently installed by rustup that should have their artifacts kept
     #[arg(long, value_delimiter = ',')]
     toolchains: Vec<String>,
 
+    /// Crates whose artifacts are always kept, regardless of the criterion
+    #[arg(long, value_delimiter = ',', value_name = "CRATES")]
+    pub exclude: Vec<String>,
+
+    /// Only remove artifacts of workspace members, keeping those of dependencies
+    ///
+    /// Works with every criterion. Artifacts that are kept still count towards
+    /// --maxsize and the like.
+    #[arg(long, conflicts_with_all = ["only_deps", "stamp", "build_dir_root"])]
+    pub only_members: bool,
+
+    /// Only remove artifacts of dependencies, keeping those of workspace members
+    ///
+    /// Works with every criterion. Artifacts that are kept still count towards
+    /// --maxsize and the like.
+    #[arg(long, conflicts_with_all = ["stamp", "build_dir_root"])]
+    pub only_deps: bool,
+
+    /// What to do when a build or another sweep is using a target folder [default: wait]
+    ///
+    /// Cargo locks a profile folder like target/debug while building in it, which cargo-sweep
+    /// respects so that it doesn't remove files the build is writing or about to link.
+    #[arg(long, value_enum, value_name = "ACTION", global = true)]
+    pub on_locked: Option<OnLocked>,
+
+    /// Output format for the results, JSON is printed to stdout one object per line while logs go to stderr [default: human]
+    #[arg(long, value_enum, value_name = "FMT", global = true)]
+    pub message_format: Option<MessageFormat>,
+
     /// Enable DEBUG logs (use twice for TRACE logs)
-    #[arg(short, long, action = clap::ArgAction::Count)]
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
     pub verbose: u8,
 }
 
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageFormat {
+    #[default]
+    Human,
+    Json,
+}
+
+/// What to do with a target folder that is locked by a build or another sweep.
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnLocked {
+    /// Wait until the lock is released
+    #[default]
+    Wait,
+    /// Leave the target folder alone
+    Skip,
+}
+
+#[derive(clap::Subcommand, Debug)]
+#[cfg_attr(test, derive(PartialEq))]
+pub enum Command {
+    /// Remove the artifacts listed in a plan file written by `--plan-out`
+    ///
+    /// Artifacts that were modified or accessed since the plan was made are skipped.
+    Apply {
+        /// Path to the plan file
+        plan: PathBuf,
+    },
+    /// Put the artifacts of a sweep with `--quarantine` back where they were
+    ///
+    /// Artifacts that were built again since are left in quarantine.
+    Restore {
+        /// The sweep to restore, named after the time it started [default: the latest]
+        run: Option<String>,
+    },
+    /// Remove the artifacts in the quarantine for good
+    Purge {
+        /// Purge only sweeps older than ... days [default: all]
+        #[arg(short, long, value_name = "DAYS")]
+        time: Option<u64>,
+    },
+    /// Show where the space of the target folder goes, without removing anything
+    ///
+    /// The units tracked by fingerprints are broken down by profile, target, crate, toolchain
+    /// and the time since they were last used, next to everything else in the target folder.
+    Report {
+        /// Path to check
+        path: Vec<PathBuf>,
+    },
+    /// Keep only the dependency artifacts of the target folder worth caching in CI
+    ///
+    /// Removes the artifacts of workspace members along with their binaries, tests and examples of
+    /// any package, incremental compilation sessions, and everything that wasn't used since
+    /// `cargo sweep --stamp` was run, which is best done at the start of the job.
+    CiPrepare {
+        /// Path to the project [default: the current directory]
+        path: Option<PathBuf>,
+    },
+    /// Run a command, then remove everything in the target folder it didn't use
+    ///
+    /// Like `--stamp` followed by `--file`, without leaving a timestamp file in the project.
+    /// The target folder of the current directory is swept, example: cargo sweep exec -- cargo test
+    Exec {
+        /// Sweep even if the command fails, which is skipped by default
+        #[arg(long)]
+        sweep_on_failure: bool,
+
+        /// The command to run, with its arguments
+        #[arg(
+            required = true,
+            trailing_var_arg = true,
+            allow_hyphen_values = true,
+            value_name = "COMMAND"
+        )]
+        command: Vec<OsString>,
+    },
+}
+
 impl Args {
-    // Might fail in case parsing size units fails.
-    pub fn criterion(&self) -> anyhow::Result<Criterion> {
-        Ok(match &self {
-            _ if self.stamp => Criterion::Stamp,
-            _ if self.file => Criterion::File,
-            _ if self.installed => Criterion::Installed,
-            _ if !self.toolchains.is_empty() => Criterion::Toolchains(self.toolchains.clone()),
-            _ if self.all => Criterion::Time(0),
-            Self {
-                time: Some(time), ..
-            } => Criterion::Time(*time),
-            Self {
-                maxsize: Some(size),
-                ..
-            } => {
-                // Try parsing as `human_size::Size` to accept "MB" and "GB" as units
-                // If it fails, fall back to `u64` and use "MB" as the default unit
-
-                let size = size
-                    .parse::<human_size::Size>()
-                    .map(human_size::Size::to_bytes)
-                    .or_else(|_| size.parse::<u64>().map(|size| size * MEGABYTE))
-                    .map_err(|_| anyhow!(format!("Failed to parse size '{size}'")))?;
-
-                Criterion::MaxSize(size)
+    /// Uses the configuration for everything that wasn't given on the command line.
+    /// The criteria from the configuration are only used if none were given on the command line.
+    pub fn with_config(mut self, config: Config) -> anyhow::Result<Self> {
+        if !self.has_criterion() {
+            if config.installed == Some(true) && config.toolchains.is_some() {
+                bail!("Only one of `installed` and `toolchains` can be configured");
             }
-            _ => unreachable!("guaranteed by clap ArgGroup"),
-        })
+            self.time = config.time;
+            self.keep_latest = config.keep_latest;
+            self.maxsize = config.maxsize.map(String::from);
+            self.total_maxsize = config.total_maxsize.map(String::from);
+            self.ensure_free = config.ensure_free.map(String::from);
+            self.installed = config.installed.unwrap_or_default();
+            self.toolchains = config.toolchains.unwrap_or_default();
+            self.unreferenced = config.unreferenced.unwrap_or_default();
+        }
+        if self.exclude.is_empty() {
+            self.exclude = config.exclude.unwrap_or_default();
+        }
+        if !self.only_members && !self.only_deps {
+            if config.only_members == Some(true) && config.only_deps == Some(true) {
+                bail!("Only one of `only-members` and `only-deps` can be configured");
+            }
+            self.only_members = config.only_members.unwrap_or_default();
+            self.only_deps = config.only_deps.unwrap_or_default();
+        }
+        if self.quarantine.is_some()
+            && (self.maxsize.is_some()
+                || self.total_maxsize.is_some()
+                || self.ensure_free.is_some())
+        {
+            bail!(
+                "The configured size limits can't be met with --quarantine, which frees no space"
+            );
+        }
+        self.message_format = self.message_format.or(config.message_format);
+        self.on_locked = self.on_locked.or(config.on_locked);
+        Ok(self)
+    }
+
+    /// Fails if flags were given that the subcommand would ignore, most of them only apply to a sweep.
+    pub fn check_command(&self) -> anyhow::Result<()> {
+        let Some(command) = &self.command else {
+            return Ok(());
+        };
+        // Whether the subcommand takes paths, --exclude and --recursive.
+        let (name, paths, exclude, recursive) = match command {
+            Command::Apply { .. } => ("apply", false, false, false),
+            Command::Restore { .. } => ("restore", false, false, false),
+            Command::Purge { .. } => ("purge", false, false, false),
+            Command::Report { .. } => ("report", true, false, true),
+            Command::CiPrepare { .. } => ("ci-prepare", true, true, false),
+            Command::Exec { .. } => ("exec", true, true, true),
+        };
+        let ignored = [
+            ("a criterion", self.has_criterion()),
+            ("--interactive", self.interactive),
+            ("--plan-out", self.plan_out.is_some()),
+            ("--build-dir-root", self.build_dir_root.is_some()),
+            ("--only-members", self.only_members),
+            ("--only-deps", self.only_deps),
+            ("a path", !paths && !self.path.is_empty()),
+            ("--exclude", !exclude && !self.exclude.is_empty()),
+            ("--recursive", !recursive && self.recursive),
+            ("--hidden", !recursive && self.hidden),
+        ];
+        if let Some((flag, _)) = ignored.into_iter().find(|(_, given)| *given) {
+            bail!("`{name}` doesn't take {flag}, see `cargo sweep {name} --help`");
+        }
+        Ok(())
+    }
+
+    /// Whether any criterion was given, on the command line or in the configuration.
+    pub fn has_criterion(&self) -> bool {
+        self.stamp
+            || self.file
+            || self.all
+            || self.time.is_some()
+            || self.installed
+            || !self.toolchains.is_empty()
+            || self.unreferenced
+            || self.keep_latest.is_some()
+            || self.maxsize.is_some()
+            || self.total_maxsize.is_some()
+            || self.ensure_free.is_some()
+    }
+
+    /// The number of days to keep artifacts for with --time or --all, if given.
+    pub fn keep_days(&self) -> Option<u64> {
+        if self.all {
+            Some(0)
+        } else {
+            self.time
+        }
+    }
+
+    /// The criteria to sweep with, in the order they are applied.
+    // Might fail in case parsing size units fails.
+    pub fn criteria(&self) -> anyhow::Result<Vec<Criterion>> {
+        if self.stamp {
+            return Ok(vec![Criterion::Stamp]);
+        }
+
+        let mut criteria = vec![];
+        if self.installed {
+            criteria.push(Criterion::Installed);
+        } else if !self.toolchains.is_empty() {
+            criteria.push(Criterion::Toolchains(self.toolchains.clone()));
+        }
+        if self.unreferenced {
+            criteria.push(Criterion::Unreferenced);
+        }
+
+        if self.file {
+            criteria.push(Criterion::File);
+        } else if self.all {
+            criteria.push(Criterion::Time(0));
+        } else if let Some(time) = self.time {
+            criteria.push(Criterion::Time(time));
+        }
+
+        if let Some(count) = self.keep_latest {
+            criteria.push(Criterion::KeepLatest(count));
+        }
+
+        if let Some(size) = &self.maxsize {
+            criteria.push(Criterion::MaxSize(parse_size(size)?));
+        }
+        if let Some(size) = &self.total_maxsize {
+            criteria.push(Criterion::TotalMaxSize(parse_size(size)?));
+        }
+        if let Some(free) = &self.ensure_free {
+            criteria.push(Criterion::EnsureFree(parse_free_space(free)?));
+        }
+
+        if criteria.is_empty() {
+            bail!(
+                "No criterion given, use one of --stamp, --file, --all, --time, --installed, \
+                --toolchains, --unreferenced, --keep-latest, --maxsize, --total-maxsize or --ensure-free, or configure one in `{}` or `[package.metadata.sweep]`",
+                crate::config::CONFIG_FILE_NAME
+            );
+        }
+        Ok(criteria)
+    }
+}
+
+fn parse_size(size: &str) -> anyhow::Result<u64> {
+    // Try parsing as `human_size::Size` to accept "MB" and "GB" as units
+    // If it fails, fall back to `u64` and use "MB" as the default unit
+    size.parse::<human_size::Size>()
+        .map(human_size::Size::to_bytes)
+        .or_else(|_| size.parse::<u64>().map(|size| size * MEGABYTE))
+        .map_err(|_| anyhow!(format!("Failed to parse size '{size}'")))
+}
+
+fn parse_free_space(free: &str) -> anyhow::Result<FreeSpace> {
+    match free.strip_suffix('%') {
+        Some(percent) => match percent.trim().parse::<u8>() {
+            Ok(percent) if percent <= 100 => Ok(FreeSpace::Percent(percent)),
+            _ => bail!("Failed to parse percentage '{free}'"),
+        },
+        None => parse_size(free).map(FreeSpace::Bytes),
     }
 }
 
@@ -132,7 +448,11 @@ pub enum Criterion {
     Time(u64),
     Installed,
     Toolchains(Vec<String>),
+    Unreferenced,
+    KeepLatest(u64),
     MaxSize(u64),
+    TotalMaxSize(u64),
+    EnsureFree(FreeSpace),
 }
 
 #[cfg(test)]
@@ -147,8 +467,8 @@ mod tests {
 
     #[test]
     fn test_argparsing() {
-        // Argument is required
-        assert!(parse("cargo sweep").is_err());
+        // Criterion is required, but may come from the config
+        assert!(parse("cargo sweep").unwrap().criteria().is_err());
         assert!(parse("cargo sweep --installed").is_ok());
         assert!(parse("cargo sweep --file").is_ok());
         assert!(parse("cargo sweep --stamp").is_ok());
@@ -156,8 +476,17 @@ mod tests {
         assert!(parse("cargo sweep --time 30").is_ok());
         assert!(parse("cargo sweep --toolchains SAMPLE_TEXT").is_ok());
         assert!(parse("cargo sweep --maxsize 100").is_ok());
+        assert!(parse("cargo sweep --total-maxsize 100").is_ok());
+        assert!(parse("cargo sweep --ensure-free 10%").is_ok());
+        assert!(parse("cargo sweep --keep-latest 2").is_ok());
+        assert!(parse("cargo sweep --keep-latest 0").is_err());
+        assert!(parse("cargo sweep --unreferenced").is_ok());
+        assert!(parse("cargo sweep --unreferenced --stamp").is_err());
+        assert!(parse("cargo sweep --time 30 --only-members").is_ok());
+        assert!(parse("cargo sweep --maxsize 100 --only-deps").is_ok());
+        assert!(parse("cargo sweep --time 30 --only-members --only-deps").is_err());
 
-        assert!(parse("cargo-sweep sweep").is_err());
+        assert!(parse("cargo-sweep sweep").unwrap().criteria().is_err());
         assert!(parse("cargo-sweep sweep --installed").is_ok());
         assert!(parse("cargo-sweep sweep --file").is_ok());
         assert!(parse("cargo-sweep sweep --stamp").is_ok());
@@ -167,13 +496,82 @@ mod tests {
         assert!(parse("cargo-sweep sweep --maxsize 100").is_ok());
 
         // Argument conflicts
-        assert!(parse("cargo sweep --installed --maxsize 100").is_err());
-        assert!(parse("cargo sweep --file --installed").is_err());
         assert!(parse("cargo sweep --stamp --file").is_err());
         assert!(parse("cargo sweep --time 30 --all").is_err());
         assert!(parse("cargo sweep --time 30 --stamp").is_err());
-        assert!(parse("cargo sweep --toolchains SAMPLE_TEXT --time 30").is_err());
-        assert!(parse("cargo sweep --maxsize 100 --toolchains SAMPLE_TEXT").is_err());
+        assert!(parse("cargo sweep --file --time 30").is_err());
+        assert!(parse("cargo sweep --installed --toolchains SAMPLE_TEXT").is_err());
+        assert!(parse("cargo sweep --total-maxsize 100 --stamp").is_err());
+        assert!(parse("cargo sweep --keep-latest 1 --stamp").is_err());
+        assert!(parse("cargo sweep --maxsize 100 --quarantine q").is_err());
+        assert!(parse("cargo sweep --quarantine q --ensure-free 10%").is_err());
+
+        // Combined criteria
+        assert!(parse("cargo sweep --installed --maxsize 100").is_ok());
+        assert!(parse("cargo sweep --file --installed").is_ok());
+        assert!(parse("cargo sweep --toolchains SAMPLE_TEXT --time 30").is_ok());
+        assert!(parse("cargo sweep --maxsize 100 --toolchains SAMPLE_TEXT").is_ok());
+        assert_eq!(
+            parse("cargo sweep --maxsize 1 --time 14 --installed")
+                .unwrap()
+                .criteria()
+                .unwrap(),
+            [
+                Criterion::Installed,
+                Criterion::Time(14),
+                Criterion::MaxSize(MEGABYTE)
+            ]
+        );
+        assert_eq!(
+            parse("cargo sweep -r --total-maxsize 10 --maxsize 2")
+                .unwrap()
+                .criteria()
+                .unwrap(),
+            [
+                Criterion::MaxSize(2 * MEGABYTE),
+                Criterion::TotalMaxSize(10 * MEGABYTE)
+            ]
+        );
+        assert_eq!(
+            parse("cargo sweep --maxsize 2 --keep-latest 3 --time 30 --unreferenced")
+                .unwrap()
+                .criteria()
+                .unwrap(),
+            [
+                Criterion::Unreferenced,
+                Criterion::Time(30),
+                Criterion::KeepLatest(3),
+                Criterion::MaxSize(2 * MEGABYTE)
+            ]
+        );
+
+        // Subcommands don't require a criterion, but can't be combined with one
+        assert!(parse("cargo sweep apply plan.json").is_ok());
+        assert!(parse("cargo sweep apply --dry-run plan.json").is_ok());
+        assert!(parse("cargo sweep apply").is_err());
+        assert!(parse("cargo sweep apply plan.json --time 30").is_err());
+        // Flags that only apply to a sweep are rejected rather than ignored by the subcommands.
+        let check = |command| parse(command).unwrap().check_command();
+        assert!(check("cargo sweep --time 30 apply plan.json").is_err());
+        assert!(check("cargo sweep -r apply plan.json").is_err());
+        assert!(check("cargo sweep --exclude tokio report").is_err());
+        assert!(check("cargo sweep --dry-run apply plan.json").is_ok());
+        assert!(check("cargo sweep --exclude tokio exec cargo test").is_ok());
+        assert!(check("cargo sweep --time 30").is_ok());
+        assert!(
+            parse("cargo sweep --dry-run apply plan.json")
+                .unwrap()
+                .dry_run
+        );
+        assert!(parse("cargo sweep -r report").unwrap().recursive);
+        assert!(parse("cargo sweep report --hidden -r").unwrap().hidden);
+        assert!(parse("cargo sweep ci-prepare").is_ok());
+        assert!(parse("cargo sweep ci-prepare --dry-run project").is_ok());
+        assert!(parse("cargo sweep ci-prepare --time 30").is_err());
+        assert!(parse("cargo sweep exec -- cargo test --workspace").is_ok());
+        assert!(parse("cargo sweep exec --sweep-on-failure cargo build -v").is_ok());
+        assert!(parse("cargo sweep exec").is_err());
+        assert!(parse("cargo sweep --time 30 --plan-out plan.json").is_ok());
 
         // Test if comma separated list is parsed correctly
         let args = Args {
@@ -199,9 +597,9 @@ mod tests {
 
         for (input, expected_size) in test_data {
             let input = format!("cargo-sweep sweep --maxsize {input}");
-            let result = parse(&input).unwrap().criterion().unwrap();
+            let result = parse(&input).unwrap().criteria().unwrap();
 
-            if result != Criterion::MaxSize(expected_size) {
+            if result != [Criterion::MaxSize(expected_size)] {
                 panic!(
                     "Test failed.\n\
                      Input: {input}\n\
@@ -211,4 +609,84 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_ensure_free_argument_parsing() {
+        let criteria = |input: &str| {
+            parse(&format!("cargo sweep --ensure-free {input}"))
+                .unwrap()
+                .criteria()
+        };
+        assert_eq!(
+            criteria("10%").unwrap(),
+            [Criterion::EnsureFree(FreeSpace::Percent(10))]
+        );
+        assert_eq!(
+            criteria("30GB").unwrap(),
+            [Criterion::EnsureFree(FreeSpace::Bytes(
+                30 * 1000 * 1000 * 1000
+            ))]
+        );
+        assert_eq!(
+            criteria("3").unwrap(),
+            [Criterion::EnsureFree(FreeSpace::Bytes(3 * MEGABYTE))]
+        );
+        assert!(criteria("101%").is_err());
+        assert!(criteria("ten%").is_err());
+    }
+
+    #[test]
+    fn test_config_defaults() {
+        let config = Config {
+            time: Some(30),
+            exclude: Some(vec!["serde".to_string()]),
+            message_format: Some(MessageFormat::Json),
+            ..Config::default()
+        };
+
+        let args = parse("cargo sweep").unwrap().with_config(config.clone());
+        let args = args.unwrap();
+        assert_eq!(args.criteria().unwrap(), [Criterion::Time(30)]);
+        assert_eq!(args.exclude, ["serde"]);
+        assert_eq!(args.message_format, Some(MessageFormat::Json));
+
+        // Flags override the config
+        let args = parse("cargo sweep --installed --exclude tokio --message-format human")
+            .unwrap()
+            .with_config(config)
+            .unwrap();
+        assert_eq!(args.criteria().unwrap(), [Criterion::Installed]);
+        assert_eq!(args.exclude, ["tokio"]);
+        assert_eq!(args.message_format, Some(MessageFormat::Human));
+
+        let combined = Config {
+            time: Some(30),
+            installed: Some(true),
+            ..Config::default()
+        };
+        let args = parse("cargo sweep").unwrap().with_config(combined).unwrap();
+        assert_eq!(
+            args.criteria().unwrap(),
+            [Criterion::Installed, Criterion::Time(30)]
+        );
+
+        let conflicting = Config {
+            installed: Some(true),
+            toolchains: Some(vec!["stable".to_string()]),
+            ..Config::default()
+        };
+        assert!(parse("cargo sweep")
+            .unwrap()
+            .with_config(conflicting)
+            .is_err());
+
+        let sized = Config {
+            maxsize: Some(crate::config::MaxSize::Size("10GB".to_string())),
+            ..Config::default()
+        };
+        assert!(parse("cargo sweep --quarantine q")
+            .unwrap()
+            .with_config(sized)
+            .is_err());
+    }
 }
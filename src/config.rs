@@ -0,0 +1,188 @@
+use anyhow::{Context, Error};
+use cargo_metadata::Metadata;
+use serde_derive::Deserialize;
+use std::{fs, path::Path};
+
+use crate::{
+    cli::{MessageFormat, OnLocked},
+    manifest_path,
+};
+
+/// Name of the configuration file looked up in the swept path and its ancestors.
+pub const CONFIG_FILE_NAME: &str = ".cargo-sweep.toml";
+
+/// Default sweep policy, used for everything that isn't given on the command line.
+///
+/// Read from `.cargo-sweep.toml`, `[package.metadata.sweep]` and `[workspace.metadata.sweep]`,
+/// in that order of precedence.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    pub time: Option<u64>,
+    pub keep_latest: Option<u64>,
+    pub maxsize: Option<MaxSize>,
+    pub total_maxsize: Option<MaxSize>,
+    pub ensure_free: Option<MaxSize>,
+    pub installed: Option<bool>,
+    pub toolchains: Option<Vec<String>>,
+    pub unreferenced: Option<bool>,
+    pub exclude: Option<Vec<String>>,
+    pub only_members: Option<bool>,
+    pub only_deps: Option<bool>,
+    pub message_format: Option<MessageFormat>,
+    pub on_locked: Option<OnLocked>,
+}
+
+/// Accepts both `maxsize = 500` and `maxsize = "10GB"`, like the `--maxsize` flag.
+/// The same goes for `total-maxsize` and `ensure-free`, which also accepts a percentage like `"10%"`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum MaxSize {
+    Megabytes(u64),
+    Size(String),
+}
+
+impl From<MaxSize> for String {
+    fn from(size: MaxSize) -> Self {
+        match size {
+            MaxSize::Megabytes(size) => size.to_string(),
+            MaxSize::Size(size) => size,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration that applies to the given path.
+    /// Cargo metadata is optional, as the path might not be a Cargo project at all, e.g. with `--recursive`.
+    pub fn load(path: &Path, metadata: Option<&Metadata>) -> Result<Self, Error> {
+        let path = &path.canonicalize().unwrap_or_else(|_| path.to_owned());
+        let mut config = Config::default();
+
+        if let Some(config_file) = path
+            .ancestors()
+            .map(|dir| dir.join(CONFIG_FILE_NAME))
+            .find(|file| file.is_file())
+        {
+            config = config.or(Self::from_file(&config_file)?);
+        }
+
+        if let Some(metadata) = metadata {
+            let manifest_path = manifest_path(path);
+            if let Some(package) = metadata
+                .packages
+                .iter()
+                .find(|package| package.manifest_path == manifest_path)
+            {
+                if let Some(sweep) = package.metadata.get("sweep") {
+                    let package_config = serde_json::from_value(sweep.clone()).context(format!(
+                        "Failed to parse [package.metadata.sweep] in {:?}",
+                        package.manifest_path
+                    ))?;
+                    config = config.or(package_config);
+                }
+            }
+
+            let workspace_manifest = metadata.workspace_root.join("Cargo.toml");
+            config = config.or(Self::from_workspace_manifest(&workspace_manifest)?);
+        }
+
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self, Error> {
+        let contents = fs::read_to_string(path).context(format!("Failed to read {path:?}"))?;
+        toml::from_str(&contents).context(format!("Failed to parse {path:?}"))
+    }
+
+    fn from_workspace_manifest(path: &Path) -> Result<Self, Error> {
+        let contents = fs::read_to_string(path).context(format!("Failed to read {path:?}"))?;
+        let manifest: toml::Table =
+            toml::from_str(&contents).context(format!("Failed to parse {path:?}"))?;
+        let Some(sweep) = manifest
+            .get("workspace")
+            .and_then(|workspace| workspace.get("metadata"))
+            .and_then(|metadata| metadata.get("sweep"))
+        else {
+            return Ok(Config::default());
+        };
+        sweep.clone().try_into().context(format!(
+            "Failed to parse [workspace.metadata.sweep] in {path:?}"
+        ))
+    }
+
+    /// Fills every setting that is missing in `self` from `other`.
+    fn or(self, other: Config) -> Config {
+        Config {
+            time: self.time.or(other.time),
+            keep_latest: self.keep_latest.or(other.keep_latest),
+            maxsize: self.maxsize.or(other.maxsize),
+            total_maxsize: self.total_maxsize.or(other.total_maxsize),
+            ensure_free: self.ensure_free.or(other.ensure_free),
+            installed: self.installed.or(other.installed),
+            toolchains: self.toolchains.or(other.toolchains),
+            unreferenced: self.unreferenced.or(other.unreferenced),
+            exclude: self.exclude.or(other.exclude),
+            only_members: self.only_members.or(other.only_members),
+            only_deps: self.only_deps.or(other.only_deps),
+            message_format: self.message_format.or(other.message_format),
+            on_locked: self.on_locked.or(other.on_locked),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config: Config = toml::from_str(
+            r#"
+            maxsize = "10GB"
+            keep-latest = 2
+            exclude = ["serde"]
+            only-deps = true
+            message-format = "json"
+            on-locked = "skip"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            Config {
+                maxsize: Some(MaxSize::Size("10GB".to_string())),
+                keep_latest: Some(2),
+                exclude: Some(vec!["serde".to_string()]),
+                only_deps: Some(true),
+                message_format: Some(MessageFormat::Json),
+                on_locked: Some(OnLocked::Skip),
+                ..Config::default()
+            }
+        );
+
+        let config: Config = toml::from_str("maxsize = 500\ntotal-maxsize = \"1TB\"").unwrap();
+        assert_eq!(config.maxsize.map(String::from), Some("500".to_string()));
+        assert_eq!(
+            config.total_maxsize.map(String::from),
+            Some("1TB".to_string())
+        );
+
+        assert!(toml::from_str::<Config>("unknown = 1").is_err());
+    }
+
+    #[test]
+    fn test_precedence() {
+        let file = Config {
+            time: Some(30),
+            ..Config::default()
+        };
+        let workspace = Config {
+            time: Some(10),
+            installed: Some(true),
+            ..Config::default()
+        };
+        let merged = file.or(workspace);
+        assert_eq!(merged.time, Some(30));
+        assert_eq!(merged.installed, Some(true));
+    }
+}
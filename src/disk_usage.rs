@@ -0,0 +1,74 @@
+use std::{collections::HashSet, fs::Metadata, path::Path};
+use walkdir::WalkDir;
+
+/// Adds up the space files take up on disk, counting every file only once even if it is
+/// reachable through several hard links, like the artifacts Cargo uplifts from `deps`.
+#[derive(Debug, Default)]
+pub(crate) struct DiskUsage {
+    /// Device and inode of every file with more than one link that was already counted.
+    seen: HashSet<(u64, u64)>,
+}
+
+impl DiskUsage {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// The space allocated to a file, or `0` if it was already counted through another link.
+    #[cfg(unix)]
+    pub(crate) fn file(&mut self, metadata: &Metadata) -> u64 {
+        use std::os::unix::fs::MetadataExt;
+        if metadata.nlink() > 1 && !self.seen.insert((metadata.dev(), metadata.ino())) {
+            return 0;
+        }
+        // `blocks` is always in units of 512 bytes, regardless of the filesystem block size.
+        metadata.blocks() * 512
+    }
+
+    /// The size of a file, there is no portable way to find its allocated space or other links.
+    #[cfg(not(unix))]
+    pub(crate) fn file(&mut self, metadata: &Metadata) -> u64 {
+        metadata.len()
+    }
+
+    /// The space allocated to the file at the given path, or to every file inside the directory.
+    pub(crate) fn path(&mut self, path: &Path) -> u64 {
+        WalkDir::new(path)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.metadata().ok())
+            .filter(|metadata| metadata.is_file())
+            .fold(0, |acc, metadata| acc + self.file(&metadata))
+    }
+}
+
+/// The space on disk taken up by the file at the given path, or by every file inside the directory.
+///
+/// Files with several hard links are counted once, so this is what removing the path would free
+/// as long as nothing outside of it links to the same files.
+pub fn disk_usage(path: &Path) -> u64 {
+    DiskUsage::new().path(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_hard_links_counted_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("libfoo-0123456789abcdef.rlib");
+        fs::write(&file, vec![1; 10_000]).unwrap();
+        let size = disk_usage(&file);
+        assert!(size > 0);
+
+        fs::hard_link(&file, dir.path().join("libfoo.rlib")).unwrap();
+        assert_eq!(disk_usage(dir.path()), size);
+
+        let mut usage = DiskUsage::new();
+        assert_eq!(usage.path(&file), size);
+        assert_eq!(usage.path(&dir.path().join("libfoo.rlib")), 0);
+    }
+}
@@ -1,5 +1,5 @@
 #![allow(deprecated)]
-use anyhow::{bail, Context, Error};
+use anyhow::{anyhow, bail, Context, Error};
 use log::trace;
 use log::{debug, info, warn};
 use rustc_stable_hash::StableSipHasher128 as StableHasher;
@@ -7,24 +7,31 @@ use serde_derive::Deserialize;
 use serde_json::from_str;
 use std::{
     collections::{HashMap, HashSet},
-    fs::{self, remove_dir_all, remove_file, File},
+    fs::{self, File},
     hash::{Hash, Hasher, SipHasher},
     io::prelude::*,
-    path::Path,
-    process::Command,
+    path::{Path, PathBuf},
+    process::{self, Command},
     time::Duration,
 };
-use walkdir::{DirEntry, WalkDir};
+
+use crate::disk_usage::DiskUsage;
+use crate::incremental::{load_sessions, lock_file};
+use crate::layout::{load_unit_dirs, lookup_all_profiles, UnitDir};
+use crate::plan::{is_named, Criterion, PlanEntry, Reason, Scope, SweepPlan};
+use crate::target::TargetDir;
+use crate::unreferenced::load_unreferenced;
+use crate::usage_log::UsageLog;
 
 /// This has to match the way Cargo hashes a rustc version.
 /// As such it is copied from Cargos code.
-fn hash_u64<H: Hash>(hashable: &H) -> u64 {
+pub(crate) fn hash_u64<H: Hash>(hashable: &H) -> u64 {
     let mut hasher = StableHasher::new();
     hashable.hash(&mut hasher);
     Hasher::finish(&hasher)
 }
 /// This version of the hash was used prior to Rust 1.85.0.
-fn hash_u64_old<H: Hash>(hashable: &H) -> u64 {
+pub(crate) fn hash_u64_old<H: Hash>(hashable: &H) -> u64 {
     let mut hasher = SipHasher::new_with_keys(0, 0);
     hashable.hash(&mut hasher);
     hasher.finish()
@@ -32,13 +39,13 @@ fn hash_u64_old<H: Hash>(hashable: &H) -> u64 {
 
 /// This has to match the way Cargo stores a rustc version in a fingerprint file.
 #[derive(Deserialize, Debug)]
-struct Fingerprint {
-    rustc: u64,
+pub(crate) struct Fingerprint {
+    pub(crate) rustc: u64,
 }
 
 /// the files and folder tracked by fingerprint have the form `({prefix}-)?{name}-{16 char hex hash}(.{extension})?`
 /// this returns `Some({hex hash})` if it is of that form and `None` otherwise.
-fn hash_from_path_name(filename: &str) -> Option<&str> {
+pub(crate) fn hash_from_path_name(filename: &str) -> Option<&str> {
     // maybe just use regex
     let name = filename.split('.').next().unwrap();
     let hash = name.rsplit('-').next().unwrap();
@@ -60,7 +67,7 @@ fn hash_from_path_name(filename: &str) -> Option<&str> {
 
 impl Fingerprint {
     /// Attempts to load the the Fingerprint data for a given fingerprint directory.
-    fn load(fingerprint_dir: &Path) -> Result<Self, Error> {
+    pub(crate) fn load(fingerprint_dir: &Path) -> Result<Self, Error> {
         for entry in fs::read_dir(fingerprint_dir)? {
             let path = entry?.path();
             if let Some(ext) = path.extension() {
@@ -78,35 +85,48 @@ impl Fingerprint {
     }
 }
 
-fn load_all_fingerprints_built_with(
-    fingerprint_dir: &Path,
-    installed_rustc: &HashSet<u64>,
-) -> Result<HashSet<String>, Error> {
-    assert_eq!(
-        fingerprint_dir
-            .file_name()
-            .expect("load takes the path to a .fingerprint directory"),
-        ".fingerprint"
-    );
-    let mut keep = HashSet::new();
-    for entry in fs::read_dir(fingerprint_dir)? {
-        let path = entry?.path();
-        if path.is_dir() {
-            let f = Fingerprint::load(&path).map(|f| installed_rustc.contains(&f.rustc));
-            // we default to keeping, as there are files that dont have the data we need.
-            if f.unwrap_or(true) {
+/// Finds the fingerprint directory of every unit in a profile, in either layout, with the hash of the unit.
+pub(crate) fn all_fingerprints(profile: &Path) -> Result<Vec<(PathBuf, String)>, Error> {
+    let mut fingerprints = vec![];
+    let fingerprint_dir = profile.join(".fingerprint");
+    if fingerprint_dir.is_dir() {
+        for entry in fs::read_dir(fingerprint_dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
                 let name = path.file_name().unwrap().to_string_lossy();
                 if let Some(hash) = hash_from_path_name(&name) {
-                    keep.insert(hash.to_string());
+                    let hash = hash.to_string();
+                    fingerprints.push((path, hash));
                 }
             }
         }
     }
+    for unit in load_unit_dirs(profile)? {
+        let fingerprint = unit.fingerprint_dir();
+        if fingerprint.is_dir() {
+            fingerprints.push((fingerprint, unit.hash));
+        }
+    }
+    Ok(fingerprints)
+}
+
+fn load_all_fingerprints_built_with(
+    profile: &Path,
+    installed_rustc: &HashSet<u64>,
+) -> Result<HashSet<String>, Error> {
+    let mut keep = HashSet::new();
+    for (path, hash) in all_fingerprints(profile)? {
+        let f = Fingerprint::load(&path).map(|f| installed_rustc.contains(&f.rustc));
+        // we default to keeping, as there are files that dont have the data we need.
+        if f.unwrap_or(true) {
+            keep.insert(hash);
+        }
+    }
     trace!("Hashs to keep: {:#?}", keep);
     Ok(keep)
 }
 
-fn last_used_time(fingerprint_dir: &Path) -> Result<Duration, Error> {
+pub(crate) fn last_used_time(fingerprint_dir: &Path) -> Result<Duration, Error> {
     let mut best = Duration::from_secs(3_155_760_000); // 100 years!
     for entry in fs::read_dir(fingerprint_dir)? {
         let accessed = entry?
@@ -121,64 +141,67 @@ fn last_used_time(fingerprint_dir: &Path) -> Result<Duration, Error> {
     Ok(best)
 }
 
-fn load_all_fingerprints_by_time(fingerprint_dir: &Path) -> Result<Vec<(Duration, String)>, Error> {
-    assert_eq!(
-        fingerprint_dir
-            .file_name()
-            .expect("load takes the path to a .fingerprint directory"),
-        ".fingerprint"
-    );
-    let mut keep = vec![];
-    for entry in fs::read_dir(fingerprint_dir)? {
-        let path = entry?.path();
-        if path.is_dir() {
-            let last_used = last_used_time(&path)?;
-            let name = path.file_name().unwrap().to_string_lossy();
-            if let Some(hash) = hash_from_path_name(&name) {
-                keep.push((last_used, hash.to_string()));
-            }
-        }
+/// The name of the package a fingerprint directory belongs to, like `serde` for `.fingerprint/serde-{hash}`.
+pub(crate) fn package_name(fingerprint_dir: &Path, hash: &str) -> String {
+    let dir_name = fingerprint_dir.file_name().unwrap().to_string_lossy();
+    match dir_name.strip_suffix(hash) {
+        // `.fingerprint/{name}-{hash}` in the classic layout
+        Some(name) => name.trim_end_matches('-').to_owned(),
+        // `build/{name}/{hash}/fingerprint` in the per-package layout
+        None => fingerprint_dir
+            .ancestors()
+            .nth(2)
+            .and_then(Path::file_name)
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
     }
-    keep.sort_unstable();
-    debug!("Hashs by time: {:#?}", keep);
-    Ok(keep)
 }
 
-fn load_all_fingerprints_newer_than(
-    fingerprint_dir: &Path,
-    keep_duration: &Duration,
-) -> Result<HashSet<String>, Error> {
-    assert_eq!(
-        fingerprint_dir
-            .file_name()
-            .expect("load takes the path to a .fingerprint directory"),
-        ".fingerprint"
-    );
-    let mut keep = HashSet::new();
+/// What a unit is a build of regardless of its hash, like `serde lib-serde` for the library of serde,
+/// so that builds of it with other features, dependencies or settings can be told apart from
+/// builds of its build script, tests or binaries.
+/// These are named by the package and the fingerprint files in the fingerprint directory.
+fn unit_kind(fingerprint_dir: &Path, hash: &str) -> Result<String, Error> {
+    let name = package_name(fingerprint_dir, hash);
+    let mut targets = vec![];
     for entry in fs::read_dir(fingerprint_dir)? {
-        let path = entry?.path();
-        if path.is_dir() && last_used_time(&path)? < *keep_duration {
-            let name = path.file_name().unwrap().to_string_lossy();
-            if let Some(hash) = hash_from_path_name(&name) {
-                keep.insert(hash.to_string());
-            }
+        let file_name = entry?.file_name();
+        if let Some(target) = file_name.to_string_lossy().strip_suffix(".json") {
+            targets.push(target.to_owned());
         }
     }
-    trace!("Hashs to keep: {:#?}", keep);
-    Ok(keep)
+    targets.sort();
+    Ok(format!("{name} {}", targets.join(" ")))
 }
 
-fn total_disk_space_dir(dir: &Path) -> u64 {
-    WalkDir::new(dir)
-        .into_iter()
-        .filter_map(|entry| entry.ok())
-        .filter_map(|entry| entry.metadata().ok())
-        .filter(|metadata| metadata.is_file())
-        .fold(0, |acc, m| acc + m.len())
+/// Whether a unit, by its [unit_kind], builds tests, benchmarks or examples rather than
+/// something that dependent packages are built from.
+fn is_test_or_example(kind: &str) -> bool {
+    kind.split(' ').skip(1).any(|target| {
+        ["test-", "integration-test-", "bench-", "example-"]
+            .iter()
+            .any(|prefix| target.starts_with(prefix))
+    })
+}
+
+fn load_all_fingerprints_by_time(
+    profile: &Path,
+) -> Result<Vec<(Duration, String, PathBuf)>, Error> {
+    let mut keep = vec![];
+    let log = UsageLog::load(profile)?;
+    for (path, hash) in all_fingerprints(profile)? {
+        keep.push((log.last_used(&hash, last_used_time(&path)?), hash, path));
+    }
+    keep.sort_unstable();
+    let by_time: Vec<_> = keep.iter().map(|(time, hash, _)| (time, hash)).collect();
+    debug!("Hashs by time: {:#?}", by_time);
+    Ok(keep)
 }
 
 fn total_disk_space_by_hash_in_a_dir(
     dir: &Path,
+    usage: &mut DiskUsage,
     disk_space: &mut HashMap<String, u64>,
 ) -> Result<(), Error> {
     for entry in fs::read_dir(dir)? {
@@ -192,9 +215,9 @@ fn total_disk_space_by_hash_in_a_dir(
 
         if let Some(hash) = hash_from_path_name(&name) {
             *disk_space.entry(hash.to_owned()).or_default() += if path.is_file() {
-                metadata.len()
+                usage.file(&metadata)
             } else if path.is_dir() {
-                total_disk_space_dir(&path)
+                usage.path(&path)
             } else {
                 panic!("what type is it!")
             };
@@ -203,12 +226,33 @@ fn total_disk_space_by_hash_in_a_dir(
     Ok(())
 }
 
-fn remove_not_matching_in_a_dir(
+/// The units to keep in a profile, and why every other file tracked by a fingerprint is removed.
+struct Removal {
+    keep: HashSet<String>,
+    /// The reason each removed unit was selected for.
+    reasons: HashMap<String, Reason>,
+    /// The reason for files whose hash doesn't belong to any unit, or `None` to keep them.
+    untracked: Option<Reason>,
+}
+
+impl Removal {
+    /// Returns why the files with the given hash are removed, or `None` if they are kept.
+    fn reason(&self, hash: &str) -> Option<&Reason> {
+        if self.keep.contains(hash) {
+            None
+        } else {
+            self.reasons.get(hash).or(self.untracked.as_ref())
+        }
+    }
+}
+
+/// Adds every file and folder in `dir` that is tracked by a fingerprint not kept to the plan.
+fn plan_not_matching_in_a_dir(
     dir: &Path,
-    keep: &HashSet<String>,
-    dry_run: bool,
-) -> Result<u64, Error> {
-    let mut total_disk_space = 0;
+    removal: &Removal,
+    usage: &mut DiskUsage,
+    plan: &mut SweepPlan,
+) -> Result<(), Error> {
     for entry in fs::read_dir(dir)? {
         let entry = entry?;
         let metadata = entry.metadata()?;
@@ -218,86 +262,258 @@ fn remove_not_matching_in_a_dir(
             .expect("folders in a directory don't have a name!?")
             .to_string_lossy();
         if let Some(hash) = hash_from_path_name(&name) {
-            if !keep.contains(hash) {
-                if path.is_file() {
-                    total_disk_space += metadata.len();
-                    if !dry_run {
-                        match remove_file(&path) {
-                            Ok(_) => debug!("Successfully removed: {:?}", &path),
-                            Err(e) => warn!("Failed to remove: {:?} {}", &path, e),
-                        };
-                    } else {
-                        debug!("Would remove: {:?}", &path);
-                    }
+            if let Some(reason) = removal.reason(hash) {
+                let size = if path.is_file() {
+                    usage.file(&metadata)
                 } else if path.is_dir() {
-                    total_disk_space += total_disk_space_dir(&path);
-                    if !dry_run {
-                        match remove_dir_all(&path) {
-                            Ok(_) => debug!("Successfully removed: {:?}", &path),
-                            Err(e) => warn!("Failed to remove: {:?} {}", &path, e),
-                        };
-                    } else {
-                        debug!("Would remove: {:?}", &path);
-                    }
-                }
+                    usage.path(&path)
+                } else {
+                    continue;
+                };
+                let hash = hash.to_owned();
+                plan.push(PlanEntry::new(path, hash, size, reason.clone()));
             }
         }
     }
-    Ok(total_disk_space)
+    Ok(())
 }
 
-fn total_disk_space_in_a_profile(dir: &Path) -> Result<HashMap<String, u64>, Error> {
-    debug!("Sizing: {:?} with total_disk_space_in_a_profile", dir);
-    let mut total_disk_space = HashMap::new();
-    total_disk_space_by_hash_in_a_dir(&dir.join(".fingerprint"), &mut total_disk_space)?;
-    total_disk_space_by_hash_in_a_dir(&dir.join("build"), &mut total_disk_space)?;
-    total_disk_space_by_hash_in_a_dir(&dir.join("deps"), &mut total_disk_space)?;
-    // examples is just final artifacts not tracked by fingerprint so skip that one.
-    // incremental is not tracked by fingerprint so skip that one.
-    // `native` isn't generated by cargo since 1.37.0
-    let native_dir = dir.join("native");
-    if native_dir.exists() {
-        total_disk_space_by_hash_in_a_dir(&native_dir, &mut total_disk_space)?;
+/// A file Cargo may uplift from, with the hash of the unit it belongs to.
+#[derive(Clone)]
+pub(crate) struct Output {
+    pub(crate) path: PathBuf,
+    name: String,
+    pub(crate) hash: String,
+}
+
+/// The files in `dir` named after the hash of their unit, like `deps/libfoo-{hash}.rlib`.
+pub(crate) fn hashed_outputs(dir: &Path) -> Result<Vec<Output>, Error> {
+    let mut outputs = vec![];
+    if !dir.is_dir() {
+        return Ok(outputs);
     }
-    total_disk_space_by_hash_in_a_dir(dir, &mut total_disk_space)?;
-    Ok(total_disk_space)
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if let Some(hash) = hash_from_path_name(&name) {
+            let hash = hash.to_owned();
+            outputs.push(Output {
+                path: entry.path(),
+                name,
+                hash,
+            });
+        }
+    }
+    Ok(outputs)
 }
 
-fn remove_not_built_with_in_a_profile(
+/// The files in the `out` directory of every unit of the per-package layout.
+pub(crate) fn unit_dir_outputs(unit_dirs: &[UnitDir]) -> Result<Vec<Output>, Error> {
+    let mut outputs = vec![];
+    for unit in unit_dirs {
+        let out_dir = unit.path.join("out");
+        if !out_dir.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(out_dir)? {
+            let entry = entry?;
+            outputs.push(Output {
+                path: entry.path(),
+                name: entry.file_name().to_string_lossy().into_owned(),
+                hash: unit.hash.clone(),
+            });
+        }
+    }
+    Ok(outputs)
+}
+
+/// Finds the files Cargo uplifted into `dir` without a hash, like `target/debug/foo`, `libfoo.rlib` or `foo.d`,
+/// together with the hash of the unit they belong to.
+///
+/// Artifacts are matched with the output they were hard linked or copied from,
+/// dep-info files belong to the artifact with the same name. Files that can't be matched are left out,
+/// like the outputs of a cdylib, which Cargo doesn't name after the hash of their unit in `deps` either.
+fn uplifted_in_a_dir(dir: &Path, outputs: &[Output]) -> Result<Vec<(PathBuf, String)>, Error> {
+    let mut artifacts = vec![];
+    let mut dep_infos = vec![];
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !entry.file_type()?.is_file()
+            || name.starts_with('.')
+            || hash_from_path_name(&name).is_some()
+        {
+            continue;
+        }
+        if name.ends_with(".d") {
+            dep_infos.push(entry.path());
+        } else {
+            artifacts.push((entry.path(), name));
+        }
+    }
+
+    let mut uplifted = vec![];
+    for (artifact, name) in artifacts {
+        // `libfoo.rlib` is uplifted from `libfoo-{hash}.rlib`, or from `out/libfoo.rlib` in the per-package layout.
+        // Binaries keep the dashes of their name, which rustc turns into underscores in `deps`.
+        let (stem, extension) = match name.split_once('.') {
+            Some((stem, extension)) => (stem.replace('-', "_"), format!(".{extension}")),
+            None => (name.replace('-', "_"), String::new()),
+        };
+        let hash = outputs
+            .iter()
+            .filter(|output| {
+                output.name == name || output.name == format!("{stem}-{}{extension}", output.hash)
+            })
+            .find(|output| is_same_file(&artifact, &output.path))
+            .map(|output| output.hash.clone());
+        if let Some(hash) = hash {
+            uplifted.push((artifact, hash));
+        }
+    }
+    for dep_info in dep_infos {
+        let hash = uplifted
+            .iter()
+            .find(|(artifact, _)| artifact.with_extension("d") == dep_info)
+            .map(|(_, hash)| hash.clone());
+        if let Some(hash) = hash {
+            uplifted.push((dep_info, hash));
+        }
+    }
+    Ok(uplifted)
+}
+
+/// Whether both paths are hard links to the same file, or copies with the same contents.
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    let (Ok(a_metadata), Ok(b_metadata)) = (fs::metadata(a), fs::metadata(b)) else {
+        return false;
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if (a_metadata.dev(), a_metadata.ino()) == (b_metadata.dev(), b_metadata.ino()) {
+            return true;
+        }
+    }
+    a_metadata.len() == b_metadata.len()
+        && matches!((fs::read(a), fs::read(b)), (Ok(a), Ok(b)) if a == b)
+}
+
+fn total_disk_space_uplifted_in_a_dir(
     dir: &Path,
-    keep: &HashSet<String>,
-    dry_run: bool,
-) -> Result<u64, Error> {
-    debug!(
-        "cleaning: {:?} with remove_not_built_with_in_a_profile",
-        dir
-    );
-    let mut total_disk_space = 0;
-    total_disk_space += remove_not_matching_in_a_dir(&dir.join("build"), keep, dry_run)?;
-    total_disk_space += remove_not_matching_in_a_dir(&dir.join("deps"), keep, dry_run)?;
-    // examples is just final artifacts not tracked by fingerprint so skip that one.
-    // incremental is not tracked by fingerprint so skip that one.
+    outputs: &[Output],
+    usage: &mut DiskUsage,
+    disk_space: &mut HashMap<String, u64>,
+) -> Result<(), Error> {
+    for (path, hash) in uplifted_in_a_dir(dir, outputs)? {
+        *disk_space.entry(hash).or_default() += usage.file(&fs::metadata(&path)?);
+    }
+    Ok(())
+}
+
+/// Adds the uplifted files found by [uplifted_in_a_dir] to the plan, if their unit is not kept.
+fn plan_uplifted(
+    uplifted: Vec<(PathBuf, String)>,
+    removal: &Removal,
+    usage: &mut DiskUsage,
+    plan: &mut SweepPlan,
+) -> Result<(), Error> {
+    for (path, hash) in uplifted {
+        if let Some(reason) = removal.reason(&hash) {
+            let size = usage.file(&fs::metadata(&path)?);
+            plan.push(PlanEntry::new(path, hash, size, reason.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// The directories of a profile in the classic layout holding files named after the hash of their unit,
+/// besides `.fingerprint`. `incremental` is not tracked by fingerprint, its sessions are handled on their own.
+fn hashed_dirs(dir: &Path) -> Vec<PathBuf> {
+    let mut dirs = vec![dir.join("build"), dir.join("deps")];
+    // examples are hashed like deps, but only exist once an example was built.
     // `native` isn't generated by cargo since 1.37.0
-    let native_dir = dir.join("native");
-    if native_dir.exists() {
-        total_disk_space += remove_not_matching_in_a_dir(&native_dir, keep, dry_run)?;
+    dirs.extend(
+        [dir.join("examples"), dir.join("native")]
+            .into_iter()
+            .filter(|dir| dir.exists()),
+    );
+    dirs.push(dir.to_owned());
+    dirs
+}
+
+/// Sizes every unit of a profile, skipping files `usage` already counted through another link.
+/// The final artifacts are looked for in `artifact_dir`, which is the profile itself unless
+/// Cargo uses a separate build directory.
+pub(crate) fn total_disk_space_in_a_profile(
+    dir: &Path,
+    artifact_dir: &Path,
+    usage: &mut DiskUsage,
+) -> Result<HashMap<String, u64>, Error> {
+    debug!("Sizing: {:?} with total_disk_space_in_a_profile", dir);
+    let mut total_disk_space = HashMap::new();
+    let unit_dirs = load_unit_dirs(dir)?;
+    for unit in &unit_dirs {
+        *total_disk_space.entry(unit.hash.clone()).or_default() += usage.path(&unit.path);
+    }
+    let unit_outputs = unit_dir_outputs(&unit_dirs)?;
+    if dir.join(".fingerprint").is_dir() {
+        total_disk_space_by_hash_in_a_dir(&dir.join(".fingerprint"), usage, &mut total_disk_space)?;
+        for hashed_dir in hashed_dirs(dir) {
+            total_disk_space_by_hash_in_a_dir(&hashed_dir, usage, &mut total_disk_space)?;
+        }
     }
-    total_disk_space += remove_not_matching_in_a_dir(dir, keep, dry_run)?;
-    total_disk_space += remove_not_matching_in_a_dir(&dir.join(".fingerprint"), keep, dry_run)?;
+    let examples_outputs = [hashed_outputs(&dir.join("examples"))?, unit_outputs.clone()].concat();
+    total_disk_space_uplifted_in_a_dir(
+        &artifact_dir.join("examples"),
+        &examples_outputs,
+        usage,
+        &mut total_disk_space,
+    )?;
+    let outputs = [hashed_outputs(&dir.join("deps"))?, unit_outputs].concat();
+    total_disk_space_uplifted_in_a_dir(artifact_dir, &outputs, usage, &mut total_disk_space)?;
     Ok(total_disk_space)
 }
 
-fn lookup_all_fingerprint_dirs(dir: &Path) -> impl Iterator<Item = DirEntry> {
-    WalkDir::new(dir)
-        .min_depth(1)
-        .into_iter()
-        .filter_map(|entry| entry.ok())
-        .filter(|e| {
-            e.file_name()
-                .to_str()
-                .map(|s| s == ".fingerprint")
-                .unwrap_or(false)
-        })
+fn plan_not_built_with_in_a_profile(
+    dir: &Path,
+    artifact_dir: &Path,
+    removal: &Removal,
+    usage: &mut DiskUsage,
+    plan: &mut SweepPlan,
+) -> Result<(), Error> {
+    debug!("planning: {:?} with plan_not_built_with_in_a_profile", dir);
+    let unit_dirs = load_unit_dirs(dir)?;
+    let unit_outputs = unit_dir_outputs(&unit_dirs)?;
+    // Matching uplifted files may read the outputs they were copied from, which is done before
+    // any entry is recorded, so that reading them doesn't make the entries look used since.
+    let examples_outputs = [hashed_outputs(&dir.join("examples"))?, unit_outputs.clone()].concat();
+    let uplifted_examples = uplifted_in_a_dir(&artifact_dir.join("examples"), &examples_outputs)?;
+    let outputs = [hashed_outputs(&dir.join("deps"))?, unit_outputs].concat();
+    let uplifted = uplifted_in_a_dir(artifact_dir, &outputs)?;
+    let classic = dir.join(".fingerprint").is_dir();
+    if classic {
+        for hashed_dir in hashed_dirs(dir) {
+            plan_not_matching_in_a_dir(&hashed_dir, removal, usage, plan)?;
+        }
+    }
+    plan_uplifted(uplifted_examples, removal, usage, plan)?;
+    plan_uplifted(uplifted, removal, usage, plan)?;
+    // Every unit of the per-package layout is removed as a whole, fingerprint included.
+    for unit in unit_dirs {
+        if let Some(reason) = removal.reason(&unit.hash) {
+            let size = usage.path(&unit.path);
+            plan.push(PlanEntry::new(unit.path, unit.hash, size, reason.clone()));
+        }
+    }
+    // The fingerprints are planned last, so an interrupted sweep never leaves untracked artifacts behind.
+    if classic {
+        plan_not_matching_in_a_dir(&dir.join(".fingerprint"), removal, usage, plan)?;
+    }
+    Ok(())
 }
 
 fn is_custom_toolchain(toolchain: &str) -> bool {
@@ -330,6 +546,18 @@ fn is_custom_toolchain(toolchain: &str) -> bool {
     true
 }
 
+/// Runs `rustc -vV` of the given toolchain, or of the default one.
+pub(crate) fn rustc_version(toolchain: Option<&str>) -> Result<process::Output, Error> {
+    let args = toolchain
+        .into_iter()
+        .map(|toolchain| format!("+{toolchain}"))
+        .chain(Some("-vV".to_string()));
+    Command::new("rustc")
+        .args(args)
+        .output()
+        .context("failed to run `rustc`")
+}
+
 fn lookup_from_names(
     iter: impl Iterator<Item = Option<impl AsRef<str>>>,
 ) -> Result<HashSet<u64>, Error> {
@@ -338,15 +566,7 @@ fn lookup_from_names(
     // This can be fixed in cargo, but for now this makes sure we don't clean the files.
     toolchain_set.insert(0);
     for x in iter {
-        let args = x
-            .as_ref()
-            .into_iter()
-            .map(|toolchain| format!("+{}", toolchain.as_ref()))
-            .chain(Some("-vV".to_string()));
-        let out = Command::new("rustc")
-            .args(args)
-            .output()
-            .context("failed to run `rustc`")?;
+        let out = rustc_version(x.as_ref().map(|toolchain| toolchain.as_ref()))?;
 
         if !out.status.success() {
             let toolchain = x.as_ref().map_or("", |t| t.as_ref());
@@ -377,7 +597,7 @@ fn lookup_from_names(
     Ok(toolchain_set)
 }
 
-fn rustup_toolchain_list() -> Option<Vec<String>> {
+pub(crate) fn rustup_toolchain_list() -> Option<Vec<String>> {
     let out = Command::new("rustup").args(["toolchain", "list"]).output();
 
     match out {
@@ -438,111 +658,544 @@ pub fn hash_toolchains(rust_versions: Option<&Vec<String>>) -> Result<HashSet<u6
     Ok(hashed_versions)
 }
 
-pub fn remove_not_built_with(
-    dir: &Path,
-    hashed_rust_version_to_keep: &HashSet<u64>,
-    dry_run: bool,
-) -> Result<u64, Error> {
-    debug!("cleaning: {:?} with remove_not_built_with", dir);
-    let mut total_disk_space = 0;
-    for fing in lookup_all_fingerprint_dirs(dir) {
-        let path = fing.into_path();
-        let keep = load_all_fingerprints_built_with(&path, hashed_rust_version_to_keep)?;
-        total_disk_space +=
-            remove_not_built_with_in_a_profile(path.parent().unwrap(), &keep, dry_run)?;
-    }
-    Ok(total_disk_space)
+/// A unit or incremental session found while planning, and the reason it is removed for, if any.
+struct PlannedUnit {
+    /// Index into the target directories being planned.
+    target: usize,
+    /// Index into the profiles of the target directory.
+    profile: usize,
+    last_used: Duration,
+    size: u64,
+    hash: String,
+    /// The package the unit belongs to, or the crate for incremental sessions.
+    package: String,
+    /// What the unit is a build of, see [unit_kind]. `None` for incremental sessions.
+    kind: Option<String>,
+    /// Whether the scope of the sweep allows removing the unit, otherwise no criterion selects it.
+    in_scope: bool,
+    /// The session directory, if this is an incremental session rather than a unit.
+    session: Option<PathBuf>,
+    reason: Option<Reason>,
+}
+
+/// Plans the sweep of a target directory, and of its build directory if it is elsewhere,
+/// applying every criterion in order.
+///
+/// A unit is removed if any of the criteria selects it. [Criterion::MaxSize] takes into account
+/// what the criteria before it already removed, so it is best placed last.
+pub fn plan_sweep(target_dir: &TargetDir, criteria: &[Criterion]) -> Result<SweepPlan, Error> {
+    plan_sweep_all(std::slice::from_ref(target_dir), criteria)
+        .pop()
+        .expect("one plan per target directory")
 }
 
-/// Attempts to sweep the cargo project located at the given path,
-/// keeping only files which have been accessed within the given duration.
-/// Dry specifies if files should actually be removed or not.
-/// Returns a list of the deleted file/dir paths.
-pub fn remove_older_than(
-    path: &Path,
-    keep_duration: &Duration,
-    dry_run: bool,
-) -> Result<u64, Error> {
-    debug!("cleaning: {:?} with remove_older_than", path);
-    let mut total_disk_space = 0;
+/// Plans the sweep of several target directories at once, returning one plan per directory.
+///
+/// This behaves like [plan_sweep] for each directory, except for [Criterion::TotalMaxSize],
+/// which removes the least recently used units of all directories until they fit together.
+/// A directory that fails to be planned doesn't affect the others, and doesn't count towards the total.
+pub fn plan_sweep_all(
+    target_dirs: &[TargetDir],
+    criteria: &[Criterion],
+) -> Vec<Result<SweepPlan, Error>> {
+    plan_sweep_scoped(target_dirs, criteria, &Scope::All)
+}
+
+/// Plans the sweep of several target directories like [plan_sweep_all], but only removes the
+/// units the scope includes. The others are kept whatever the criteria, while still taking up
+/// space for [Criterion::MaxSize] and the like.
+pub fn plan_sweep_scoped(
+    target_dirs: &[TargetDir],
+    criteria: &[Criterion],
+    scope: &Scope,
+) -> Vec<Result<SweepPlan, Error>> {
+    let needs_sizes = criteria.iter().any(|criterion| {
+        matches!(
+            criterion,
+            Criterion::MaxSize(_) | Criterion::TotalMaxSize(_) | Criterion::EnsureFree(_)
+        )
+    });
+
+    let mut units = vec![];
+    let mut targets: Vec<Result<Vec<PathBuf>, Error>> = target_dirs
+        .iter()
+        .enumerate()
+        .map(|(target, target_dir)| load_units(target, target_dir, needs_sizes, scope, &mut units))
+        .collect();
 
-    for fing in lookup_all_fingerprint_dirs(path) {
-        let path = fing.into_path();
-        let keep = load_all_fingerprints_newer_than(&path, keep_duration)?;
-        total_disk_space +=
-            remove_not_built_with_in_a_profile(path.parent().unwrap(), &keep, dry_run)?;
+    // The reason for the first criterion that selects anything in a target directory
+    // is also used for the untracked files of that directory.
+    let mut untracked: Vec<Option<Reason>> = vec![None; target_dirs.len()];
+    for criterion in criteria {
+        let mut applies = vec![false; target_dirs.len()];
+        match criterion {
+            Criterion::NotBuiltWith(hashed_rust_version_to_keep) => {
+                for (target, profiles) in targets.iter_mut().enumerate() {
+                    let Ok(dirs) = profiles else {
+                        continue;
+                    };
+                    let keep: Result<Vec<_>, _> = dirs
+                        .iter()
+                        .map(|profile| {
+                            load_all_fingerprints_built_with(profile, hashed_rust_version_to_keep)
+                        })
+                        .collect();
+                    match keep {
+                        Ok(keep) => {
+                            select(&mut units, criterion, |unit| {
+                                // There is no telling which compiler an incremental session belongs to
+                                unit.target == target
+                                    && unit.session.is_none()
+                                    && !keep[unit.profile].contains(&unit.hash)
+                            });
+                            applies[target] = true;
+                        }
+                        Err(e) => *profiles = Err(e),
+                    }
+                }
+            }
+            Criterion::Unreferenced(graph) => {
+                for (target, profiles) in targets.iter_mut().enumerate() {
+                    let Ok(dirs) = profiles else {
+                        continue;
+                    };
+                    let unreferenced: Result<Vec<_>, _> = dirs
+                        .iter()
+                        .map(|profile| load_unreferenced(profile, graph))
+                        .collect();
+                    match unreferenced {
+                        Ok(unreferenced) => {
+                            select(&mut units, criterion, |unit| {
+                                unit.target == target
+                                    && unit.session.is_none()
+                                    && unreferenced[unit.profile].contains(&unit.hash)
+                            });
+                            applies[target] = true;
+                        }
+                        Err(e) => *profiles = Err(e),
+                    }
+                }
+            }
+            Criterion::OlderThan(keep_duration) => {
+                select(&mut units, criterion, |unit| {
+                    unit.last_used >= *keep_duration
+                });
+                applies.fill(true);
+            }
+            Criterion::KeepLatest(count) => {
+                let mut latest: Vec<&PlannedUnit> = units
+                    .iter()
+                    .filter(|unit| unit.kind.is_some() && unit.reason.is_none())
+                    .collect();
+                latest.sort_by_key(|unit| unit.last_used);
+                // Counts the builds of every kind in each profile, from most to least recently used.
+                let mut builds: HashMap<(usize, usize, &str), u64> = HashMap::new();
+                let older: HashSet<(usize, usize, String)> = latest
+                    .into_iter()
+                    .filter(|unit| {
+                        let kind = unit.kind.as_deref().unwrap();
+                        let seen = builds.entry((unit.target, unit.profile, kind)).or_default();
+                        *seen += 1;
+                        *seen > *count
+                    })
+                    .map(|unit| (unit.target, unit.profile, unit.hash.clone()))
+                    .collect();
+                select(&mut units, criterion, |unit| {
+                    unit.session.is_none()
+                        && older.contains(&(unit.target, unit.profile, unit.hash.clone()))
+                });
+                applies.fill(true);
+            }
+            Criterion::NotCacheable(members) => {
+                select(&mut units, criterion, |unit| match &unit.kind {
+                    Some(kind) => is_named(members, &unit.package) || is_test_or_example(kind),
+                    None => true,
+                });
+                applies.fill(true);
+            }
+            Criterion::MaxSize(target_size) => {
+                for (target, target_dir) in target_dirs.iter().enumerate() {
+                    if targets[target].is_ok() {
+                        applies[target] = select_older_until_fits(
+                            target_dir.disk_usage(),
+                            units.iter_mut().filter(|unit| unit.target == target),
+                            *target_size,
+                            criterion,
+                        );
+                    }
+                }
+            }
+            Criterion::TotalMaxSize(target_size) => {
+                let total_size = target_dirs
+                    .iter()
+                    .zip(&targets)
+                    .filter(|(_, profiles)| profiles.is_ok())
+                    .map(|(target_dir, _)| target_dir.disk_usage())
+                    .sum();
+                if select_older_until_fits(
+                    total_size,
+                    units.iter_mut().filter(|unit| targets[unit.target].is_ok()),
+                    *target_size,
+                    criterion,
+                ) {
+                    applies.fill(true);
+                }
+            }
+            Criterion::EnsureFree(free_space) => {
+                for filesystem in group_by_filesystem(target_dirs, &targets) {
+                    let stats = match fs4::statvfs(target_dirs[filesystem[0]].build_dir()) {
+                        Ok(stats) => stats,
+                        Err(e) => {
+                            for target in filesystem {
+                                targets[target] = Err(anyhow!(
+                                    "Failed to read the free space of the filesystem: {e}"
+                                ));
+                            }
+                            continue;
+                        }
+                    };
+                    let needed = free_space
+                        .bytes(stats.total_space())
+                        .saturating_sub(stats.available_space());
+                    if select_older_until_freed(
+                        units
+                            .iter_mut()
+                            .filter(|unit| filesystem.contains(&unit.target)),
+                        needed,
+                        criterion,
+                    ) {
+                        for target in filesystem {
+                            applies[target] = true;
+                        }
+                    }
+                }
+            }
+        }
+        for (untracked, applies) in untracked.iter_mut().zip(applies) {
+            if applies && untracked.is_none() {
+                *untracked = Some(criterion.reason());
+            }
+        }
     }
 
-    Ok(total_disk_space)
+    targets
+        .into_iter()
+        .zip(untracked)
+        .enumerate()
+        .map(|(target, (profiles, untracked))| {
+            let mut plan = SweepPlan::new();
+            // Shared by the whole target directory, so files hard linked between profiles are counted once.
+            let mut usage = DiskUsage::new();
+            for (index, profile) in profiles?.iter().enumerate() {
+                let mut keep = HashSet::new();
+                let mut reasons = HashMap::new();
+                for unit in units
+                    .iter()
+                    .filter(|unit| unit.target == target && unit.profile == index)
+                {
+                    match (&unit.session, &unit.reason) {
+                        (Some(session), Some(reason)) => {
+                            plan_session(session, &unit.hash, reason, &mut usage, &mut plan)
+                        }
+                        (Some(_), None) => {}
+                        (None, Some(reason)) => {
+                            reasons.insert(unit.hash.clone(), reason.clone());
+                        }
+                        (None, None) => {
+                            keep.insert(unit.hash.clone());
+                        }
+                    }
+                }
+                if let Some(untracked) = &untracked {
+                    let removal = Removal {
+                        keep,
+                        reasons,
+                        untracked: Some(untracked.clone()),
+                    };
+                    plan_not_built_with_in_a_profile(
+                        profile,
+                        &target_dirs[target].artifact_dir(profile),
+                        &removal,
+                        &mut usage,
+                        &mut plan,
+                    )?;
+                }
+            }
+            Ok(plan)
+        })
+        .collect()
 }
 
-pub fn remove_older_until_fits(path: &Path, target_size: u64, dry_run: bool) -> Result<u64, Error> {
-    debug!("cleaning: {:?} with remove_older_until_fits", path);
-    let starting_size = total_disk_space_dir(path);
-    if starting_size <= target_size {
-        // already below target
-        return Ok(0);
+/// Plans the removal of the units with the given hashes from every profile of a target directory,
+/// e.g. as chosen by hand from [crate::Profile::units]. Everything else is kept.
+pub fn plan_selected(target_dir: &TargetDir, hashes: &HashSet<String>) -> Result<SweepPlan, Error> {
+    let mut plan = SweepPlan::new();
+    let mut usage = DiskUsage::new();
+    for profile in lookup_all_profiles(target_dir.build_dir()) {
+        let removal = Removal {
+            keep: HashSet::new(),
+            reasons: all_fingerprints(&profile)?
+                .into_iter()
+                .filter(|(_, hash)| hashes.contains(hash))
+                .map(|(_, hash)| (hash, Reason::Selected))
+                .collect(),
+            untracked: None,
+        };
+        if !removal.reasons.is_empty() {
+            let artifact_dir = target_dir.artifact_dir(&profile);
+            plan_not_built_with_in_a_profile(
+                &profile,
+                &artifact_dir,
+                &removal,
+                &mut usage,
+                &mut plan,
+            )?;
+        }
     }
-    let size_to_remove = starting_size - target_size;
-    debug!("size_to_remove: {:?}", size_to_remove);
+    Ok(plan)
+}
 
-    let fingerprint_dirs: Vec<DirEntry> = lookup_all_fingerprint_dirs(path).collect();
-    let mut order: Vec<(Duration, u64, &Path, String)> = vec![];
-    for fing in &fingerprint_dirs {
-        let path = fing.path();
-        let sizes = total_disk_space_in_a_profile(path.parent().unwrap())?;
-        for (last_used, hash) in load_all_fingerprints_by_time(path)? {
-            order.push((
+/// Adds the units of every profile in the target directory to `units`.
+/// Returns the profiles of the target directory, which the units refer to by index.
+fn load_units(
+    target: usize,
+    target_dir: &TargetDir,
+    needs_sizes: bool,
+    scope: &Scope,
+    units: &mut Vec<PlannedUnit>,
+) -> Result<Vec<PathBuf>, Error> {
+    debug!("planning: {:?} with plan_sweep", target_dir.path());
+    let profiles = lookup_all_profiles(target_dir.build_dir());
+    let mut loaded = vec![];
+    let mut usage = DiskUsage::new();
+    for (index, profile) in profiles.iter().enumerate() {
+        let sizes = if needs_sizes {
+            let artifact_dir = target_dir.artifact_dir(profile);
+            total_disk_space_in_a_profile(profile, &artifact_dir, &mut usage)?
+        } else {
+            HashMap::new()
+        };
+        let profile_units = loaded.len();
+        for (last_used, hash, path) in load_all_fingerprints_by_time(profile)? {
+            let package = package_name(&path, &hash);
+            loaded.push(PlannedUnit {
+                target,
+                profile: index,
                 last_used,
-                *(sizes.get(&hash).unwrap_or(&0)),
-                fing.path(),
+                size: *(sizes.get(&hash).unwrap_or(&0)),
+                kind: Some(unit_kind(&path, &hash)?),
+                in_scope: scope.includes(&package),
+                package,
                 hash,
-            ));
+                session: None,
+                reason: None,
+            });
+        }
+        // Superseded sessions are never used again, so they are removed regardless of the criteria.
+        for session in load_sessions(profile)? {
+            let in_scope = scope.includes(&session.name);
+            // rustc doesn't touch the current session of a crate when there is nothing to rebuild,
+            // so it was last used along with the most recently used unit of the crate.
+            let last_used = if session.superseded {
+                session.last_used
+            } else {
+                loaded[profile_units..]
+                    .iter()
+                    .filter(|unit| {
+                        unit.session.is_none() && unit.package.replace('-', "_") == session.name
+                    })
+                    .map(|unit| unit.last_used)
+                    .fold(session.last_used, Duration::min)
+            };
+            loaded.push(PlannedUnit {
+                in_scope,
+                target,
+                profile: index,
+                last_used,
+                size: if needs_sizes {
+                    usage.path(&session.path)
+                } else {
+                    0
+                },
+                hash: session.hash,
+                package: session.name,
+                kind: None,
+                reason: (in_scope && session.superseded).then_some(Reason::Superseded),
+                session: Some(session.path),
+            });
         }
     }
+    units.append(&mut loaded);
+    Ok(profiles)
+}
 
-    // as Duration is first in the elements of order this sorts items from new to old
-    order.sort();
+/// Adds an incremental session and its lock file to the plan.
+fn plan_session(
+    session: &Path,
+    hash: &str,
+    reason: &Reason,
+    usage: &mut DiskUsage,
+    plan: &mut SweepPlan,
+) {
+    let size = usage.path(session);
+    plan.push(PlanEntry::new(
+        session.to_owned(),
+        hash.to_owned(),
+        size,
+        reason.clone(),
+    ));
+    if let Some(lock) = lock_file(session).filter(|lock| lock.is_file()) {
+        let size = fs::metadata(&lock).map_or(0, |metadata| usage.file(&metadata));
+        plan.push(PlanEntry::new(lock, hash.to_owned(), size, reason.clone()));
+    }
+}
 
-    let mut removed = 0u64;
-    // organized keeps track of what needs to be keep per fingerprint dirs
-    let mut organized = HashMap::new();
-    let mut printed = false;
+/// Marks every unit that isn't removed yet and matches the predicate as removed by the criterion.
+fn select(
+    units: &mut [PlannedUnit],
+    criterion: &Criterion,
+    mut predicate: impl FnMut(&PlannedUnit) -> bool,
+) {
+    for unit in units.iter_mut() {
+        if unit.in_scope && unit.reason.is_none() && predicate(unit) {
+            unit.reason = Some(criterion.reason());
+        }
+    }
+}
 
-    for dir in &fingerprint_dirs {
-        // populate organized with keeping nothing in each fingerprint dirs
-        organized.entry(dir.path()).or_insert_with(HashSet::new);
+/// Marks the least recently used of the given units as removed by the criterion until their
+/// directories, currently `current_size` bytes, are no larger than `target_size` bytes,
+/// taking units that are already removed into account.
+/// Returns whether anything had to be removed.
+fn select_older_until_fits<'a>(
+    current_size: u64,
+    units: impl Iterator<Item = &'a mut PlannedUnit>,
+    target_size: u64,
+    criterion: &Criterion,
+) -> bool {
+    let (removed, mut order): (Vec<_>, Vec<_>) = units.partition(|unit| unit.reason.is_some());
+    let already_removed: u64 = removed.iter().map(|unit| unit.size).sum();
+    // Units out of scope take up space all the same, but can't be removed to make up for it.
+    order.retain(|unit| unit.in_scope);
+    let starting_size = current_size.saturating_sub(already_removed);
+    if starting_size <= target_size {
+        // already below target
+        return false;
     }
+    let size_to_remove = starting_size - target_size;
+    debug!("size_to_remove: {:?}", size_to_remove);
 
-    for (last_used, sizes, fing, hash) in order.into_iter().rev() {
-        if removed + sizes < size_to_remove {
-            removed += sizes;
+    sort_old_to_new(&mut order);
+    let mut removed = 0u64;
+    let mut printed = false;
+    for unit in order {
+        if removed + unit.size < size_to_remove {
+            removed += unit.size;
+            unit.reason = Some(criterion.reason());
             continue;
         }
         if !printed {
             // TODO: consider formatting better for printing
-            info!("Removing older than: {:?}", &last_used);
+            info!("Removing older than: {:?}", &unit.last_used);
             printed = true;
         }
-        organized
-            .entry(fing)
-            .or_insert_with(HashSet::new)
-            .insert(hash);
     }
+    true
+}
 
-    let mut total_disk_space = 0;
+/// Marks the least recently used of the given units as removed by the criterion until at least
+/// `needed` bytes are freed, taking units that are already removed into account.
+/// Returns whether anything had to be removed.
+fn select_older_until_freed<'a>(
+    units: impl Iterator<Item = &'a mut PlannedUnit>,
+    needed: u64,
+    criterion: &Criterion,
+) -> bool {
+    let (removed, mut order): (Vec<_>, Vec<_>) = units.partition(|unit| unit.reason.is_some());
+    let already_removed: u64 = removed.iter().map(|unit| unit.size).sum();
+    if already_removed >= needed {
+        // already enough free space
+        return false;
+    }
+    let mut size_to_remove = needed - already_removed;
+    order.retain(|unit| unit.in_scope);
+    debug!("size_to_remove: {:?}", size_to_remove);
 
-    for (fing, keep) in organized {
-        total_disk_space +=
-            remove_not_built_with_in_a_profile(fing.parent().unwrap(), &keep, dry_run)?;
+    sort_old_to_new(&mut order);
+    for unit in order {
+        if size_to_remove == 0 {
+            info!("Removing older than: {:?}", &unit.last_used);
+            break;
+        }
+        size_to_remove = size_to_remove.saturating_sub(unit.size);
+        unit.reason = Some(criterion.reason());
     }
+    true
+}
 
-    Ok(total_disk_space)
+fn sort_old_to_new(units: &mut [&mut PlannedUnit]) {
+    units.sort_by(|a, b| {
+        (b.last_used, b.size, b.target, b.profile, &b.hash).cmp(&(
+            a.last_used,
+            a.size,
+            a.target,
+            a.profile,
+            &a.hash,
+        ))
+    });
+}
+
+/// Groups the target directories that could be planned by the filesystem holding their build directory,
+/// as they share its free space. Returns the indices of the target directories in each group.
+fn group_by_filesystem(
+    target_dirs: &[TargetDir],
+    targets: &[Result<Vec<PathBuf>, Error>],
+) -> Vec<Vec<usize>> {
+    let mut groups: Vec<(Option<u64>, Vec<usize>)> = vec![];
+    for (target, target_dir) in target_dirs.iter().enumerate() {
+        if targets[target].is_err() {
+            continue;
+        }
+        // The units are in the build directory, so that is where space is freed.
+        let id = filesystem_id(target_dir.build_dir());
+        match groups
+            .iter_mut()
+            .find(|(other, _)| id.is_some() && *other == id)
+        {
+            Some((_, group)) => group.push(target),
+            None => groups.push((id, vec![target])),
+        }
+    }
+    groups.into_iter().map(|(_, group)| group).collect()
+}
+
+/// Identifies the filesystem holding the path, if supported on this platform.
+#[cfg(unix)]
+fn filesystem_id(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path).ok().map(|metadata| metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn filesystem_id(_path: &Path) -> Option<u64> {
+    None
 }
 
 #[cfg(test)]
 mod tests {
-    use super::is_custom_toolchain;
+    use super::{is_custom_toolchain, is_test_or_example};
+
+    #[test]
+    fn test_test_or_example() {
+        assert!(is_test_or_example("serde test-lib-serde"));
+        assert!(is_test_or_example("foo test-integration-test-it"));
+        assert!(is_test_or_example("foo example-demo"));
+        assert!(is_test_or_example("foo test-bench-speed"));
+        assert!(is_test_or_example("foo integration-test-it"));
+        assert!(!is_test_or_example("serde lib-serde"));
+        assert!(!is_test_or_example("serde build-script-build-script-build"));
+        assert!(!is_test_or_example("foo bin-foo"));
+    }
 
     #[test]
     fn test_custom_toolchain() {
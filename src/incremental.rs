@@ -0,0 +1,118 @@
+use anyhow::Error;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::fingerprint::last_used_time;
+
+/// An incremental compilation session, e.g. `incremental/foo-1abc/s-hn9y416ptw-1ybudbw-7zbvopk`.
+///
+/// Sessions are not tracked by fingerprints, so they are swept on their own.
+pub(crate) struct Session {
+    pub(crate) path: PathBuf,
+    /// The crate name as used by rustc, with `-` replaced by `_`.
+    pub(crate) name: String,
+    /// The hash of the crate directory the session is in.
+    pub(crate) hash: String,
+    pub(crate) last_used: Duration,
+    /// Whether there is a newer session for the same crate. rustc only ever uses the newest one.
+    pub(crate) superseded: bool,
+}
+
+/// Loads every finished session in the `incremental` directory of a profile.
+/// Sessions that rustc is still working on end in `-working` and are left alone.
+pub(crate) fn load_sessions(profile: &Path) -> Result<Vec<Session>, Error> {
+    let mut sessions = vec![];
+    let incremental_dir = profile.join("incremental");
+    if !incremental_dir.is_dir() {
+        return Ok(sessions);
+    }
+    for entry in fs::read_dir(incremental_dir)? {
+        let crate_dir = entry?.path();
+        let Some((name, hash)) = crate_dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.rsplit_once('-'))
+        else {
+            continue;
+        };
+        if !crate_dir.is_dir() {
+            continue;
+        }
+
+        let mut crate_sessions = vec![];
+        for entry in fs::read_dir(&crate_dir)? {
+            let path = entry?.path();
+            let file_name = path.file_name().unwrap().to_string_lossy();
+            if !path.is_dir() || !file_name.starts_with("s-") || file_name.ends_with("-working") {
+                continue;
+            }
+            crate_sessions.push(Session {
+                last_used: last_used_time(&path)?,
+                path,
+                name: name.to_owned(),
+                hash: hash.to_owned(),
+                superseded: false,
+            });
+        }
+        // The session names start with a timestamp, which breaks ties.
+        crate_sessions.sort_by(|a, b| a.last_used.cmp(&b.last_used).then(b.path.cmp(&a.path)));
+        for session in crate_sessions.iter_mut().skip(1) {
+            session.superseded = true;
+        }
+        sessions.append(&mut crate_sessions);
+    }
+    Ok(sessions)
+}
+
+/// The lock file rustc keeps next to a session directory, named `s-{timestamp}-{random}.lock`.
+pub(crate) fn lock_file(session: &Path) -> Option<PathBuf> {
+    let (base, _svh) = session.file_name()?.to_str()?.rsplit_once('-')?;
+    Some(session.with_file_name(format!("{base}.lock")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_sessions() {
+        let profile = tempfile::tempdir().unwrap();
+        let crate_dir = profile
+            .path()
+            .join("incremental/sample_project-3trp1xsmlj33h");
+        for session in [
+            "s-hn9y416ptw-1ybudbw-7zbvopk7f4e6x5mv1stumyd0w",
+            "s-hn9y417sf5-0a1inik-2qesrmskdz5wd3ngjmvlvnsu8",
+            "s-hn9y418abc-0a1inik-working",
+        ] {
+            fs::create_dir_all(crate_dir.join(session)).unwrap();
+        }
+        fs::write(crate_dir.join("s-hn9y416ptw-1ybudbw.lock"), "").unwrap();
+
+        let sessions = load_sessions(profile.path()).unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert!(sessions
+            .iter()
+            .all(|session| session.name == "sample_project"));
+        assert!(sessions
+            .iter()
+            .all(|session| session.hash == "3trp1xsmlj33h"));
+        let superseded: Vec<_> = sessions
+            .iter()
+            .filter(|session| session.superseded)
+            .map(|session| session.path.clone())
+            .collect();
+        assert_eq!(
+            superseded,
+            [crate_dir.join("s-hn9y416ptw-1ybudbw-7zbvopk7f4e6x5mv1stumyd0w")]
+        );
+
+        assert_eq!(
+            lock_file(&superseded[0]),
+            Some(crate_dir.join("s-hn9y416ptw-1ybudbw.lock"))
+        );
+    }
+}
@@ -0,0 +1,576 @@
+use anyhow::Context;
+use cargo_sweep::{format_bytes, SweepPlan, TargetDir, Toolchains};
+use crossterm::{
+    cursor::{Hide, MoveTo, Show},
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    queue,
+    style::{Attribute, Print, SetAttribute},
+    terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use log::warn;
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::{self, Write},
+    time::Duration,
+};
+
+/// A unit that can be chosen for removal.
+#[derive(Debug)]
+struct UnitItem {
+    hash: String,
+    size: u64,
+    last_used: Duration,
+    toolchain: String,
+    selected: bool,
+}
+
+/// The units of a single crate or build script in a profile.
+#[derive(Debug)]
+struct CrateGroup {
+    name: String,
+    units: Vec<UnitItem>,
+    expanded: bool,
+}
+
+/// The units of a profile, e.g. `target/debug`, grouped by crate name.
+#[derive(Debug)]
+struct ProfileGroup {
+    /// Index into the target directories the list was made for.
+    target: usize,
+    label: String,
+    crates: Vec<CrateGroup>,
+    expanded: bool,
+}
+
+/// A visible line of the list, by index into the groups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Row {
+    Profile(usize),
+    Crate(usize, usize),
+    Unit(usize, usize, usize),
+}
+
+/// Whether all, some or none of the units of a row are selected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Check {
+    All,
+    Some,
+    None,
+}
+
+/// The state of the list: what is expanded, selected and under the cursor.
+#[derive(Debug)]
+struct Selection {
+    profiles: Vec<ProfileGroup>,
+    cursor: usize,
+}
+
+impl Selection {
+    /// The rows that are visible, skipping the contents of collapsed groups.
+    fn rows(&self) -> Vec<Row> {
+        let mut rows = vec![];
+        for (p, profile) in self.profiles.iter().enumerate() {
+            rows.push(Row::Profile(p));
+            if !profile.expanded {
+                continue;
+            }
+            for (c, krate) in profile.crates.iter().enumerate() {
+                rows.push(Row::Crate(p, c));
+                if krate.expanded {
+                    rows.extend((0..krate.units.len()).map(|u| Row::Unit(p, c, u)));
+                }
+            }
+        }
+        rows
+    }
+
+    /// Every unit in a row, which is all units of the group for group rows.
+    fn units(&self, row: Row) -> Vec<&UnitItem> {
+        match row {
+            Row::Profile(p) => self.profiles[p]
+                .crates
+                .iter()
+                .flat_map(|krate| &krate.units)
+                .collect(),
+            Row::Crate(p, c) => self.profiles[p].crates[c].units.iter().collect(),
+            Row::Unit(p, c, u) => vec![&self.profiles[p].crates[c].units[u]],
+        }
+    }
+
+    fn units_mut(&mut self, row: Row) -> Vec<&mut UnitItem> {
+        match row {
+            Row::Profile(p) => self.profiles[p]
+                .crates
+                .iter_mut()
+                .flat_map(|krate| &mut krate.units)
+                .collect(),
+            Row::Crate(p, c) => self.profiles[p].crates[c].units.iter_mut().collect(),
+            Row::Unit(p, c, u) => vec![&mut self.profiles[p].crates[c].units[u]],
+        }
+    }
+
+    fn check(&self, row: Row) -> Check {
+        let units = self.units(row);
+        let selected = units.iter().filter(|unit| unit.selected).count();
+        if selected == 0 {
+            Check::None
+        } else if selected == units.len() {
+            Check::All
+        } else {
+            Check::Some
+        }
+    }
+
+    /// Selects every unit of the row, or deselects them if all of them are selected already.
+    fn toggle(&mut self, row: Row) {
+        let selected = self.check(row) != Check::All;
+        for unit in self.units_mut(row) {
+            unit.selected = selected;
+        }
+    }
+
+    /// Selects every unit in the list, or deselects them if all of them are selected already.
+    fn toggle_all(&mut self) {
+        let all = (0..self.profiles.len()).all(|p| self.check(Row::Profile(p)) == Check::All);
+        for p in 0..self.profiles.len() {
+            for unit in self.units_mut(Row::Profile(p)) {
+                unit.selected = !all;
+            }
+        }
+    }
+
+    /// Expands the group under the cursor.
+    fn expand(&mut self) {
+        match self.rows().get(self.cursor) {
+            Some(Row::Profile(p)) => self.profiles[*p].expanded = true,
+            Some(Row::Crate(p, c)) => self.profiles[*p].crates[*c].expanded = true,
+            _ => {}
+        }
+    }
+
+    /// Collapses the group under the cursor, or the group the row under the cursor is in.
+    fn collapse(&mut self) {
+        let Some(&row) = self.rows().get(self.cursor) else {
+            return;
+        };
+        let group = match row {
+            Row::Profile(p) => Row::Profile(p),
+            Row::Crate(p, c) if self.profiles[p].crates[c].expanded => Row::Crate(p, c),
+            Row::Crate(p, _) => Row::Profile(p),
+            Row::Unit(p, c, _) => Row::Crate(p, c),
+        };
+        match group {
+            Row::Profile(p) => self.profiles[p].expanded = false,
+            Row::Crate(p, c) => self.profiles[p].crates[c].expanded = false,
+            Row::Unit(..) => unreachable!(),
+        }
+        self.cursor = self.rows().iter().position(|r| *r == group).unwrap_or(0);
+    }
+
+    fn move_cursor(&mut self, by: isize) {
+        let last = self.rows().len().saturating_sub(1);
+        self.cursor = self.cursor.saturating_add_signed(by).min(last);
+    }
+
+    /// The number of selected units and their combined size.
+    fn total(&self) -> (usize, u64) {
+        (0..self.profiles.len())
+            .flat_map(|p| self.units(Row::Profile(p)))
+            .filter(|unit| unit.selected)
+            .fold((0, 0), |(count, size), unit| (count + 1, size + unit.size))
+    }
+
+    /// The hashes of the selected units in each target directory.
+    fn selected_hashes(&self, targets: usize) -> Vec<HashSet<String>> {
+        let mut hashes = vec![HashSet::new(); targets];
+        for (p, profile) in self.profiles.iter().enumerate() {
+            for unit in self.units(Row::Profile(p)) {
+                if unit.selected {
+                    hashes[profile.target].insert(unit.hash.clone());
+                }
+            }
+        }
+        hashes
+    }
+
+    /// The text of a row, without the cursor.
+    fn line(&self, row: Row) -> String {
+        let check = match self.check(row) {
+            Check::All => "[x]",
+            Check::Some => "[-]",
+            Check::None => "[ ]",
+        };
+        let arrow = |expanded| if expanded { "v" } else { ">" };
+        let units = self.units(row);
+        let size: u64 = units.iter().map(|unit| unit.size).sum();
+        // The most recent use of any unit in the group.
+        let last_used = units.iter().map(|unit| unit.last_used).min();
+        let age = last_used.map(format_age).unwrap_or_default();
+        match row {
+            Row::Profile(p) => {
+                let profile = &self.profiles[p];
+                format!(
+                    "{} {check} {}  {} units  {}",
+                    arrow(profile.expanded),
+                    profile.label,
+                    units.len(),
+                    format_bytes(size)
+                )
+            }
+            Row::Crate(p, c) => {
+                let krate = &self.profiles[p].crates[c];
+                format!(
+                    "  {} {check} {:<32} {:>3} {:>12} {:>12}",
+                    arrow(krate.expanded),
+                    krate.name,
+                    units.len(),
+                    format_bytes(size),
+                    age
+                )
+            }
+            Row::Unit(p, c, u) => {
+                let unit = &self.profiles[p].crates[c].units[u];
+                format!(
+                    "      {check} {:<32} {:>12} {:>12}  {}",
+                    unit.hash,
+                    format_bytes(size),
+                    age,
+                    unit.toolchain
+                )
+            }
+        }
+    }
+}
+
+/// Formats the time since a unit was last used, e.g. `3 days`.
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    let (amount, unit) = match secs {
+        0..=59 => return "just now".to_string(),
+        60..=3599 => (secs / 60, "minute"),
+        3600..=86399 => (secs / 3600, "hour"),
+        _ => (secs / 86400, "day"),
+    };
+    let plural = if amount == 1 { "" } else { "s" };
+    format!("{amount} {unit}{plural} ago")
+}
+
+/// Lists the units of every target directory, with the ones in the plans selected.
+fn load(
+    target_dirs: &[TargetDir],
+    plans: &[anyhow::Result<SweepPlan>],
+    toolchains: &Toolchains,
+) -> Selection {
+    let mut profiles = vec![];
+    for (target, (target_dir, plan)) in target_dirs.iter().zip(plans).enumerate() {
+        let planned: HashSet<&str> = match plan {
+            Ok(plan) => plan
+                .entries()
+                .iter()
+                .map(|entry| entry.hash.as_str())
+                .collect(),
+            Err(_) => HashSet::new(),
+        };
+        for profile in target_dir.profiles() {
+            let units = match profile.units() {
+                Ok(units) => units,
+                Err(e) => {
+                    warn!("Failed to load the units in {:?}: {e:?}", profile.path());
+                    continue;
+                }
+            };
+            let mut crates: BTreeMap<String, Vec<UnitItem>> = BTreeMap::new();
+            for unit in units {
+                let toolchain = match unit.rustc {
+                    // Build script outputs aren't built by any compiler.
+                    Some(0) => String::new(),
+                    Some(rustc) => toolchains.get(rustc).map_or_else(
+                        || "not installed".to_string(),
+                        |toolchain| toolchain.label(),
+                    ),
+                    None => "unknown".to_string(),
+                };
+                crates.entry(unit.name).or_default().push(UnitItem {
+                    selected: planned.contains(unit.hash.as_str()),
+                    hash: unit.hash,
+                    size: unit.size,
+                    last_used: unit.last_used,
+                    toolchain,
+                });
+            }
+            if crates.is_empty() {
+                continue;
+            }
+            profiles.push(ProfileGroup {
+                target,
+                label: profile.path().display().to_string(),
+                crates: crates
+                    .into_iter()
+                    .map(|(name, units)| CrateGroup {
+                        name,
+                        units,
+                        expanded: false,
+                    })
+                    .collect(),
+                expanded: true,
+            });
+        }
+    }
+    Selection {
+        profiles,
+        cursor: 0,
+    }
+}
+
+/// Restores the terminal when dropped, even if drawing fails.
+struct Screen;
+
+impl Screen {
+    fn enter() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        let screen = Screen;
+        crossterm::execute!(io::stdout(), EnterAlternateScreen, Hide)?;
+        Ok(screen)
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = crossterm::execute!(io::stdout(), Show, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+const HELP: &str =
+    "up/down: move  space: select  left/right: collapse/expand  a: select all  enter: sweep  q: quit";
+
+fn draw(selection: &Selection, offset: &mut usize, confirming: bool) -> io::Result<()> {
+    let (width, height) = terminal::size()?;
+    let (width, height) = (usize::from(width), usize::from(height));
+    // One line for the title, two for the total and the keys.
+    let list_height = height.saturating_sub(3).max(1);
+    if selection.cursor < *offset {
+        *offset = selection.cursor;
+    } else if selection.cursor >= *offset + list_height {
+        *offset = selection.cursor + 1 - list_height;
+    }
+
+    let fit = |line: String| line.chars().take(width).collect::<String>();
+    let mut out = io::stdout();
+    queue!(out, Clear(ClearType::All), MoveTo(0, 0))?;
+    queue!(
+        out,
+        SetAttribute(Attribute::Bold),
+        Print(fit("Choose the units to sweep".to_string())),
+        SetAttribute(Attribute::Reset)
+    )?;
+    let rows = selection.rows();
+    for (line, (index, row)) in rows
+        .iter()
+        .enumerate()
+        .skip(*offset)
+        .take(list_height)
+        .enumerate()
+    {
+        queue!(out, MoveTo(0, line as u16 + 1))?;
+        if index == selection.cursor {
+            queue!(out, SetAttribute(Attribute::Reverse))?;
+        }
+        queue!(
+            out,
+            Print(fit(selection.line(*row))),
+            SetAttribute(Attribute::Reset)
+        )?;
+    }
+
+    let (count, size) = selection.total();
+    let total = format!("Selected {count} units, {}", format_bytes(size));
+    let keys = if confirming {
+        format!("Sweep {count} units freeing {}? [y/n]", format_bytes(size))
+    } else {
+        HELP.to_string()
+    };
+    queue!(
+        out,
+        MoveTo(0, height.saturating_sub(2) as u16),
+        SetAttribute(Attribute::Bold),
+        Print(fit(total)),
+        SetAttribute(Attribute::Reset),
+        MoveTo(0, height.saturating_sub(1) as u16),
+        Print(fit(keys))
+    )?;
+    out.flush()
+}
+
+/// The hashes of the units chosen in a target directory.
+pub type Chosen = (TargetDir, HashSet<String>);
+
+/// Lets the user choose the units to sweep in the terminal, starting with the units in the plans selected.
+///
+/// Returns the target directories with any selected units and the hashes of those units,
+/// or `None` if the user quit without confirming.
+pub fn choose(
+    target_dirs: &[TargetDir],
+    plans: &[anyhow::Result<SweepPlan>],
+    toolchains: &Toolchains,
+) -> anyhow::Result<Option<Vec<Chosen>>> {
+    let mut selection = load(target_dirs, plans, toolchains);
+    if selection.profiles.is_empty() {
+        return Ok(Some(vec![]));
+    }
+
+    let screen = Screen::enter().context("Failed to set up the terminal")?;
+    let mut offset = 0;
+    let mut confirming = false;
+    let confirmed = loop {
+        draw(&selection, &mut offset, confirming)?;
+        let Event::Key(KeyEvent {
+            code,
+            modifiers,
+            kind,
+            ..
+        }) = event::read()?
+        else {
+            continue;
+        };
+        if kind == KeyEventKind::Release {
+            continue;
+        }
+        if modifiers.contains(KeyModifiers::CONTROL) && code == KeyCode::Char('c') {
+            break false;
+        }
+        if confirming {
+            match code {
+                KeyCode::Char('y') | KeyCode::Enter => break true,
+                _ => confirming = false,
+            }
+            continue;
+        }
+        let page = terminal::size()?.1.saturating_sub(3).max(1) as isize;
+        match code {
+            KeyCode::Up | KeyCode::Char('k') => selection.move_cursor(-1),
+            KeyCode::Down | KeyCode::Char('j') => selection.move_cursor(1),
+            KeyCode::PageUp => selection.move_cursor(-page),
+            KeyCode::PageDown => selection.move_cursor(page),
+            KeyCode::Home => selection.cursor = 0,
+            KeyCode::End => selection.move_cursor(isize::MAX),
+            KeyCode::Left | KeyCode::Char('h') => selection.collapse(),
+            KeyCode::Right | KeyCode::Char('l') => selection.expand(),
+            KeyCode::Char(' ') => {
+                if let Some(&row) = selection.rows().get(selection.cursor) {
+                    selection.toggle(row);
+                }
+            }
+            KeyCode::Char('a') => selection.toggle_all(),
+            KeyCode::Enter => confirming = selection.total().0 > 0,
+            KeyCode::Char('q') | KeyCode::Esc => break false,
+            _ => {}
+        }
+    };
+    drop(screen);
+
+    if !confirmed {
+        return Ok(None);
+    }
+    Ok(Some(
+        target_dirs
+            .iter()
+            .cloned()
+            .zip(selection.selected_hashes(target_dirs.len()))
+            .filter(|(_, hashes)| !hashes.is_empty())
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit(hash: &str, size: u64, selected: bool) -> UnitItem {
+        UnitItem {
+            hash: hash.to_string(),
+            size,
+            last_used: Duration::from_secs(3600),
+            toolchain: "stable".to_string(),
+            selected,
+        }
+    }
+
+    fn selection() -> Selection {
+        Selection {
+            profiles: vec![ProfileGroup {
+                target: 0,
+                label: "target/debug".to_string(),
+                crates: vec![
+                    CrateGroup {
+                        name: "serde".to_string(),
+                        units: vec![unit("a", 1, true), unit("b", 2, false)],
+                        expanded: false,
+                    },
+                    CrateGroup {
+                        name: "tokio".to_string(),
+                        units: vec![unit("c", 4, false)],
+                        expanded: false,
+                    },
+                ],
+                expanded: true,
+            }],
+            cursor: 0,
+        }
+    }
+
+    #[test]
+    fn test_toggle_groups() {
+        let mut selection = selection();
+        assert_eq!(selection.check(Row::Profile(0)), Check::Some);
+        assert_eq!(selection.check(Row::Crate(0, 0)), Check::Some);
+        assert_eq!(selection.total(), (1, 1));
+
+        selection.toggle(Row::Crate(0, 0));
+        assert_eq!(selection.check(Row::Crate(0, 0)), Check::All);
+        assert_eq!(selection.total(), (2, 3));
+        selection.toggle(Row::Crate(0, 0));
+        assert_eq!(selection.total(), (0, 0));
+
+        selection.toggle_all();
+        assert_eq!(selection.total(), (3, 7));
+        selection.toggle(Row::Unit(0, 1, 0));
+        assert_eq!(
+            selection.selected_hashes(1),
+            [HashSet::from(["a".to_string(), "b".to_string()])]
+        );
+    }
+
+    #[test]
+    fn test_expand_and_collapse() {
+        let mut selection = selection();
+        assert_eq!(
+            selection.rows(),
+            [Row::Profile(0), Row::Crate(0, 0), Row::Crate(0, 1)]
+        );
+
+        selection.move_cursor(1);
+        selection.expand();
+        assert_eq!(selection.rows().len(), 5);
+        selection.move_cursor(2);
+        assert_eq!(selection.rows()[selection.cursor], Row::Unit(0, 0, 1));
+
+        // Collapsing from a unit jumps to its crate.
+        selection.collapse();
+        assert_eq!(selection.rows()[selection.cursor], Row::Crate(0, 0));
+        selection.collapse();
+        assert_eq!(selection.rows(), [Row::Profile(0)]);
+        assert_eq!(selection.cursor, 0);
+
+        selection.move_cursor(10);
+        assert_eq!(selection.cursor, 0);
+    }
+
+    #[test]
+    fn test_format_age() {
+        assert_eq!(format_age(Duration::from_secs(5)), "just now");
+        assert_eq!(format_age(Duration::from_secs(60)), "1 minute ago");
+        assert_eq!(format_age(Duration::from_secs(7200)), "2 hours ago");
+        assert_eq!(format_age(Duration::from_secs(3 * 86400)), "3 days ago");
+    }
+}
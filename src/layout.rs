@@ -0,0 +1,100 @@
+use anyhow::Error;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+/// A unit in Cargo's per-package build directory layout, e.g. `build/serde/0123456789abcdef`.
+///
+/// Unlike the classic layout, where all units share `deps/` and `.fingerprint/`, every unit keeps
+/// its fingerprint in `fingerprint/` and its outputs in `out/`, so the whole directory belongs to it.
+pub(crate) struct UnitDir {
+    pub(crate) path: PathBuf,
+    pub(crate) package: String,
+    pub(crate) hash: String,
+}
+
+impl UnitDir {
+    pub(crate) fn fingerprint_dir(&self) -> PathBuf {
+        self.path.join("fingerprint")
+    }
+}
+
+/// Whether the name is a bare 16 hex digit hash, like the unit directories of the per-package layout.
+pub(crate) fn is_hash(name: &str) -> bool {
+    name.len() == 16 && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Loads every unit directory of the per-package layout in a profile.
+/// The build script outputs of the classic layout, `build/foo-{hash}`, are left alone.
+pub(crate) fn load_unit_dirs(profile: &Path) -> Result<Vec<UnitDir>, Error> {
+    let mut units = vec![];
+    let build_dir = profile.join("build");
+    if !build_dir.is_dir() {
+        return Ok(units);
+    }
+    for entry in fs::read_dir(build_dir)? {
+        let package_dir = entry?.path();
+        if !package_dir.is_dir() {
+            continue;
+        }
+        let package = package_dir
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        for entry in fs::read_dir(&package_dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            if path.is_dir() && is_hash(&name) {
+                units.push(UnitDir {
+                    path,
+                    package: package.clone(),
+                    hash: name,
+                });
+            }
+        }
+    }
+    Ok(units)
+}
+
+/// Finds every profile in the given directory, in either layout: directories holding a `.fingerprint` folder,
+/// or a unit directory `build/{package}/{hash}/fingerprint`. This includes profiles nested below a target triple.
+pub(crate) fn lookup_all_profiles(dir: &Path) -> Vec<PathBuf> {
+    let mut profiles = vec![];
+    for entry in WalkDir::new(dir)
+        .min_depth(1)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_dir())
+    {
+        let path = entry.path();
+        let profile = match entry.file_name().to_str() {
+            Some(".fingerprint") => path.parent(),
+            Some("fingerprint") => {
+                let mut ancestors = path.ancestors().skip(1);
+                let is_unit_dir = ancestors
+                    .next()
+                    .and_then(|unit| unit.file_name())
+                    .and_then(|name| name.to_str())
+                    .is_some_and(is_hash);
+                let is_build_dir = ancestors
+                    .nth(1)
+                    .is_some_and(|build| build.file_name().is_some_and(|name| name == "build"));
+                if is_unit_dir && is_build_dir {
+                    ancestors.next()
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        if let Some(profile) = profile {
+            if !profiles.iter().any(|p| p == profile) {
+                profiles.push(profile.to_owned());
+            }
+        }
+    }
+    profiles
+}
@@ -0,0 +1,50 @@
+//! Library interface of `cargo-sweep`.
+//!
+//! The `cargo sweep` binary is a thin command line wrapper around this crate, which can be used
+//! directly to inspect a Cargo target directory and to remove unused build artifacts from it.
+//!
+//! ```no_run
+//! use cargo_sweep::TargetDir;
+//!
+//! let target = TargetDir::new("target");
+//! for profile in target.profiles() {
+//!     for unit in profile.units()? {
+//!         println!("{} {} {}", unit.name, unit.hash, unit.size);
+//!     }
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+mod build_root;
+mod disk_usage;
+mod fingerprint;
+mod incremental;
+mod layout;
+mod lock;
+mod plan;
+mod quarantine;
+mod report;
+mod stamp;
+mod target;
+mod toolchain;
+mod unreferenced;
+mod usage_log;
+mod util;
+
+pub use self::build_root::{
+    load_workspace_build_dirs, plan_sweep_build_root, workspace_path_hash, WorkspaceBuildDir,
+};
+pub use self::disk_usage::disk_usage;
+pub use self::fingerprint::{
+    hash_toolchains, plan_selected, plan_sweep, plan_sweep_all, plan_sweep_scoped,
+};
+pub use self::lock::{TargetLock, CARGO_LOCK_FILE, SWEEP_LOCK_FILE};
+pub use self::plan::{Criterion, FreeSpace, PlanEntry, Reason, Scope, SweepPlan};
+pub use self::quarantine::{Quarantine, QuarantineRun, QuarantinedEntry};
+pub use self::report::{Report, Usage};
+pub use self::stamp::Timestamp;
+pub use self::target::{Profile, TargetDir, Unit};
+pub use self::toolchain::{Toolchain, Toolchains};
+pub use self::unreferenced::DependencyGraph;
+pub use self::usage_log::{compact_usage_log, rustc_wrapper, USAGE_LOG_FILE};
+pub use self::util::{format_bytes, format_bytes_or_nothing};
@@ -0,0 +1,145 @@
+use anyhow::{Context, Error};
+use log::{debug, info};
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    path::{Path, PathBuf},
+};
+
+use crate::plan::SweepPlan;
+use crate::target::TargetDir;
+
+/// Name of the lock file Cargo holds in a profile directory while building, e.g. `target/debug/.cargo-lock`.
+pub const CARGO_LOCK_FILE: &str = ".cargo-lock";
+
+/// Name of the lock file cargo-sweep holds in a target directory while sweeping it.
+pub const SWEEP_LOCK_FILE: &str = ".cargo-sweep-lock";
+
+/// Exclusive advisory locks on a target directory, which keep Cargo from building in it and other
+/// sweeps from removing anything from it. The locks are released when this is dropped.
+#[derive(Debug)]
+pub struct TargetLock {
+    /// Only kept open, closing a file releases its lock.
+    _files: Vec<File>,
+}
+
+impl TargetLock {
+    /// Locks the target directory against other sweeps, and every profile in it against Cargo,
+    /// by taking the same lock Cargo takes on `.cargo-lock` while building.
+    ///
+    /// With `wait`, blocks until every lock is released, otherwise returns `None` if any of them is held.
+    pub fn lock(target_dir: &TargetDir, wait: bool) -> Result<Option<Self>, Error> {
+        let mut paths = vec![];
+        if let Some(dir) = [target_dir.path(), target_dir.build_dir()]
+            .into_iter()
+            .find(|dir| dir.is_dir())
+        {
+            paths.push(dir.join(SWEEP_LOCK_FILE));
+        }
+        for profile in target_dir.profiles() {
+            for dir in [
+                profile.path().to_owned(),
+                target_dir.artifact_dir(profile.path()),
+            ] {
+                let cargo_lock = dir.join(CARGO_LOCK_FILE);
+                if cargo_lock.is_file() && !paths.contains(&cargo_lock) {
+                    paths.push(cargo_lock);
+                }
+            }
+        }
+        Self::lock_all(&paths, wait)
+    }
+
+    /// Locks the profiles and target directories the entries of a plan are in, like [TargetLock::lock].
+    /// These are the closest directories above every entry holding a lock file.
+    pub fn lock_plan(plan: &SweepPlan, wait: bool) -> Result<Option<Self>, Error> {
+        let mut paths = vec![];
+        for entry in plan.entries() {
+            for name in [SWEEP_LOCK_FILE, CARGO_LOCK_FILE] {
+                let lock_file = entry
+                    .path
+                    .ancestors()
+                    .skip(1)
+                    .map(|dir| dir.join(name))
+                    .find(|file| file.is_file());
+                if let Some(lock_file) = lock_file {
+                    if !paths.contains(&lock_file) {
+                        paths.push(lock_file);
+                    }
+                }
+            }
+        }
+        Self::lock_all(&paths, wait)
+    }
+
+    /// Takes every lock, or none of them.
+    ///
+    /// Locks are never waited for while holding others, so a build waiting for one of them
+    /// can't end up waiting for a sweep that waits for the build in turn.
+    fn lock_all(paths: &[PathBuf], wait: bool) -> Result<Option<Self>, Error> {
+        'retry: loop {
+            let mut files = vec![];
+            for path in paths {
+                let file = open(path)?;
+                match file.try_lock() {
+                    Ok(()) => files.push(file),
+                    Err(TryLockError::WouldBlock) if wait => {
+                        drop(files);
+                        info!("Blocking waiting for file lock on {:?}", path);
+                        file.lock().context(format!("Failed to lock {path:?}"))?;
+                        continue 'retry;
+                    }
+                    Err(TryLockError::WouldBlock) => {
+                        debug!("{:?} is locked", path);
+                        return Ok(None);
+                    }
+                    Err(TryLockError::Error(e)) => {
+                        return Err(e).context(format!("Failed to lock {path:?}"))
+                    }
+                }
+            }
+            return Ok(Some(Self { _files: files }));
+        }
+    }
+}
+
+/// Opens a lock file, creating it if it is cargo-sweep's own.
+/// Cargo's lock files are never created, a profile without one isn't being built in.
+fn open(path: &Path) -> Result<File, Error> {
+    let create = path.file_name().is_some_and(|name| name == SWEEP_LOCK_FILE);
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(create)
+        .truncate(false)
+        .open(path)
+        .context(format!("Failed to open lock file {path:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let profile = dir.path().join("debug");
+        fs::create_dir_all(profile.join(".fingerprint")).unwrap();
+        fs::write(profile.join(CARGO_LOCK_FILE), "").unwrap();
+        let target_dir = TargetDir::new(dir.path());
+
+        let lock = TargetLock::lock(&target_dir, false).unwrap().unwrap();
+        assert_eq!(lock._files.len(), 2);
+        assert!(dir.path().join(SWEEP_LOCK_FILE).is_file());
+        // Another sweep can't lock it at the same time.
+        assert!(TargetLock::lock(&target_dir, false).unwrap().is_none());
+        drop(lock);
+
+        // Neither can it while Cargo is building.
+        let build = File::open(profile.join(CARGO_LOCK_FILE)).unwrap();
+        build.lock().unwrap();
+        assert!(TargetLock::lock(&target_dir, false).unwrap().is_none());
+        drop(build);
+        assert!(TargetLock::lock(&target_dir, false).unwrap().is_some());
+    }
+}
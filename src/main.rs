@@ -1,38 +1,49 @@
-use anyhow::Context;
-use cargo_metadata::{Error, Metadata, MetadataCommand};
+use anyhow::{bail, Context};
+use cargo_metadata::Metadata;
 use crossterm::tty::IsTty;
 use fern::colors::{Color, ColoredLevelConfig};
 
 use log::{debug, error, info, warn};
+use serde_derive::Deserialize;
 use std::{
+    collections::{BTreeMap, HashMap, HashSet},
     env,
-    ffi::OsStr,
+    ffi::{OsStr, OsString},
     path::{Path, PathBuf},
+    process::ExitStatus,
     time::Duration,
 };
 use walkdir::WalkDir;
 
+use cargo_sweep::{
+    compact_usage_log, format_bytes, format_bytes_or_nothing, hash_toolchains, plan_selected,
+    plan_sweep_all, plan_sweep_build_root, plan_sweep_scoped, Criterion as SweepCriterion,
+    DependencyGraph, Quarantine, QuarantineRun, Reason, Report, Scope, SweepPlan, TargetDir,
+    TargetLock, Timestamp, Toolchains,
+};
+
 mod cli;
-mod fingerprint;
-mod stamp;
-mod util;
+mod config;
+mod interactive;
+mod output;
 
-use self::cli::Criterion;
-use self::fingerprint::{
-    hash_toolchains, remove_not_built_with, remove_older_than, remove_older_until_fits,
-};
-use self::stamp::Timestamp;
-use self::util::{format_bytes, format_bytes_or_nothing};
+use self::cli::{Args, Command, Criterion, MessageFormat, OnLocked};
+use self::config::Config;
+use self::output::Message;
 
 /// Setup logging according to verbose flag.
-fn setup_logging(verbosity_level: u8) {
+/// With JSON output, logs are written to stderr so that stdout only contains JSON.
+fn setup_logging(verbosity_level: u8, message_format: MessageFormat) {
     let level = match verbosity_level {
         0 => log::LevelFilter::Info,
         1 => log::LevelFilter::Debug,
         2.. => log::LevelFilter::Trace,
     };
 
-    let isatty = std::io::stdout().is_tty();
+    let (isatty, output): (bool, fern::Output) = match message_format {
+        MessageFormat::Human => (std::io::stdout().is_tty(), std::io::stdout().into()),
+        MessageFormat::Json => (std::io::stderr().is_tty(), std::io::stderr().into()),
+    };
 
     // Configure colors for each log line
     let level_colors = ColoredLevelConfig::new()
@@ -52,17 +63,17 @@ fn setup_logging(verbosity_level: u8) {
             }
         })
         .level(level)
-        .chain(std::io::stdout())
+        .chain(output)
         .apply()
         .unwrap();
 }
 
-/// Returns whether the given path to a Cargo.toml points to a real target directory.
-fn is_cargo_root(path: &Path) -> Option<PathBuf> {
+/// Returns the target directory of the given path to a Cargo.toml, if it or its build directory exists.
+fn is_cargo_root(path: &Path) -> Option<ProjectMetadata> {
     if let Ok(metadata) = metadata(path) {
-        let out = Path::new(&metadata.target_directory).to_path_buf();
-        if out.exists() {
-            return Some(out);
+        let target_dir = metadata.target_dir();
+        if target_dir.path().exists() || target_dir.build_dir().exists() {
+            return Some(metadata);
         }
     }
     None
@@ -77,8 +88,10 @@ fn is_hidden(entry: &walkdir::DirEntry) -> bool {
 }
 
 /// Find all cargo project under the given root path.
-fn find_cargo_projects(root: &Path, include_hidden: bool) -> Vec<PathBuf> {
-    let mut target_paths = std::collections::BTreeSet::new();
+/// Projects sharing a target folder are all returned, as their workspaces may differ.
+fn find_cargo_projects(root: &Path, include_hidden: bool) -> Vec<ProjectMetadata> {
+    let mut projects: Vec<ProjectMetadata> = vec![];
+    let mut target_dirs: Vec<TargetDir> = vec![];
 
     let mut iter = WalkDir::new(root).min_depth(1).into_iter();
 
@@ -90,7 +103,12 @@ fn find_cargo_projects(root: &Path, include_hidden: bool) -> Vec<PathBuf> {
                     iter.skip_current_dir();
                     continue;
                 }
-                if entry.path().ancestors().any(|a| target_paths.contains(a)) {
+                let is_swept = |a: &Path| {
+                    target_dirs
+                        .iter()
+                        .any(|target_dir| target_dir.path() == a || target_dir.build_dir() == a)
+                };
+                if entry.path().ancestors().any(is_swept) {
                     // no reason to look at the contents of something we are already cleaning.
                     // Yes ancestors is a inefficient way to check. We can use a trie or something if it is slow.
                     iter.skip_current_dir();
@@ -100,172 +118,908 @@ fn find_cargo_projects(root: &Path, include_hidden: bool) -> Vec<PathBuf> {
             if entry.file_name() != "Cargo.toml" {
                 continue;
             }
-            if let Some(target_directory) = is_cargo_root(entry.path()) {
-                target_paths.insert(target_directory);
+            if let Some(project) = is_cargo_root(entry.path()) {
+                let target_dir = project.target_dir();
+                if !target_dirs.contains(&target_dir) {
+                    target_dirs.push(target_dir);
+                }
+                projects.push(project);
                 // Previously cargo-sweep skipped subdirectories here, but it is valid for
                 // subdirectories to contain cargo roots.
             }
         }
     }
-    target_paths.into_iter().collect()
+    projects.sort_by(|a, b| {
+        a.metadata
+            .target_directory
+            .cmp(&b.metadata.target_directory)
+    });
+    projects
 }
 
-fn metadata(path: &Path) -> Result<Metadata, Error> {
-    let manifest_path = if path.file_name().and_then(OsStr::to_str) == Some("Cargo.toml") {
+/// The path to the `Cargo.toml` of a project directory, or the path itself if it already points to one.
+fn manifest_path(path: &Path) -> PathBuf {
+    if path.file_name().and_then(OsStr::to_str) == Some("Cargo.toml") {
         path.to_owned()
     } else {
         path.join("Cargo.toml")
+    }
+}
+
+/// The output of `cargo metadata`, including the fields `cargo_metadata` doesn't know about yet.
+#[derive(Deserialize)]
+struct ProjectMetadata {
+    #[serde(flatten)]
+    metadata: Metadata,
+    /// Where Cargo keeps intermediate artifacts, see `build.build-dir`. Only reported since Cargo 1.91.
+    build_directory: Option<PathBuf>,
+}
+
+impl ProjectMetadata {
+    fn target_dir(&self) -> TargetDir {
+        let target_dir = TargetDir::new(&self.metadata.target_directory);
+        match &self.build_directory {
+            Some(build_directory) => target_dir.with_build_dir(build_directory),
+            None => target_dir,
+        }
+    }
+}
+
+fn metadata(path: &Path) -> anyhow::Result<ProjectMetadata> {
+    cargo_metadata(path, "--no-deps")
+}
+
+/// The metadata of a project with its whole dependency graph, for all features and platforms.
+fn dependency_metadata(path: &Path) -> anyhow::Result<ProjectMetadata> {
+    cargo_metadata(path, "--all-features")
+}
+
+fn cargo_metadata(path: &Path, option: &str) -> anyhow::Result<ProjectMetadata> {
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let output = std::process::Command::new(cargo)
+        .args([
+            "metadata",
+            "--format-version",
+            "1",
+            option,
+            "--manifest-path",
+        ])
+        .arg(manifest_path(path))
+        .output()?;
+    if !output.status.success() {
+        bail!(
+            "Error during execution of `cargo metadata`: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let stdout = String::from_utf8(output.stdout)?;
+    let json = stdout
+        .lines()
+        .find(|line| line.starts_with('{'))
+        .context("`cargo metadata` did not output JSON")?;
+    Ok(serde_json::from_str(json)?)
+}
+
+/// Handles the plan of every swept project according to the command line options.
+struct Sweeper {
+    dry_run: bool,
+    message_format: MessageFormat,
+    /// Crates whose units are removed from every plan.
+    exclude: Vec<String>,
+    /// Collects the plans of all projects when using `--plan-out`.
+    planned: Option<SweepPlan>,
+    /// Where swept artifacts are moved to instead of being removed when using `--quarantine`.
+    quarantine: Option<QuarantineRun>,
+    /// The known toolchains when sweeping with `--installed` or `--toolchains`, to tell which
+    /// toolchains built the units that were removed.
+    toolchains: Option<Toolchains>,
+    total_cleaned: u64,
+}
+
+impl Sweeper {
+    /// Executes the plan for a single project, or only prints it on a dry run.
+    /// When collecting a plan for `--plan-out`, the plan is added to it as well.
+    /// Returns the amount of bytes cleaned.
+    fn sweep_project(&mut self, target_dir: &TargetDir, mut plan: SweepPlan) -> u64 {
+        let project_path = target_dir.path();
+        if !self.exclude.is_empty() {
+            match target_dir.hashes_of(&self.exclude) {
+                Ok(excluded) => plan.retain(|entry| !excluded.contains(&entry.hash)),
+                Err(e) => {
+                    error!("Failed to look up excluded crates in {project_path:?}: {e:?}");
+                    return 0;
+                }
+            }
+        }
+
+        // Looked up before the units are removed, as their fingerprints tell which toolchain built them.
+        let labels = self
+            .toolchains
+            .as_ref()
+            .map(|toolchains| toolchain_labels(target_dir, toolchains));
+
+        let swept = if self.dry_run {
+            plan.print();
+            plan
+        } else if let Some(quarantine) = &self.quarantine {
+            match quarantine.quarantine(plan) {
+                Ok(moved) => moved,
+                Err(e) => {
+                    error!(
+                        "{:?}",
+                        e.context(format!("Failed to clean {project_path:?}"))
+                    );
+                    return 0;
+                }
+            }
+        } else {
+            plan.execute()
+        };
+
+        if !self.dry_run {
+            // The usage logs only need to remember the units that are left.
+            for profile in target_dir.profiles() {
+                if let Err(e) = compact_usage_log(profile.path()) {
+                    warn!("{:?}", e);
+                }
+            }
+        }
+
+        let cleaned_amount = swept.total_size();
+        if self.dry_run {
+            info!(
+                "Would clean: {} from {project_path:?}",
+                format_bytes_or_nothing(cleaned_amount)
+            );
+        } else if self.quarantine.is_some() {
+            info!(
+                "Quarantined {} from {project_path:?}",
+                format_bytes_or_nothing(cleaned_amount)
+            );
+        } else {
+            info!(
+                "Cleaned {} from {project_path:?}",
+                format_bytes_or_nothing(cleaned_amount)
+            );
+        }
+        if let Some(labels) = &labels {
+            for (label, size) in swept_by_toolchain(&swept, labels) {
+                info!("  {} built by {label}", format_bytes(size));
+            }
+        }
+
+        if self.message_format == MessageFormat::Json {
+            for entry in swept.entries() {
+                Message::Artifact {
+                    dry_run: self.dry_run,
+                    entry,
+                }
+                .emit();
+            }
+            Message::Project {
+                dry_run: self.dry_run,
+                path: project_path,
+                cleaned: cleaned_amount,
+            }
+            .emit();
+        }
+
+        if let Some(planned) = &mut self.planned {
+            let mut swept = swept;
+            swept.set_target_dir(project_path);
+            planned.merge(swept);
+        }
+        self.total_cleaned += cleaned_amount;
+        cleaned_amount
+    }
+
+    /// Reports the total and writes the collected plan for `--plan-out`.
+    fn finish(self, plan_out: Option<&Path>) -> anyhow::Result<()> {
+        if self.message_format == MessageFormat::Json {
+            Message::Total {
+                dry_run: self.dry_run,
+                cleaned: self.total_cleaned,
+            }
+            .emit();
+        }
+        if let (Some(plan_out), Some(planned)) = (plan_out, &self.planned) {
+            planned.store(plan_out)?;
+            info!("Wrote plan to {plan_out:?}");
+        }
+        if let Some(run) = &self.quarantine {
+            run.remove_if_empty()?;
+            if run.path().exists() {
+                info!(
+                    "Restore with `cargo sweep restore --quarantine {:?} {}`",
+                    run.path().parent().unwrap_or(run.path()),
+                    run.id()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The label of the toolchain that built every unit in the target folder, by the hash of the unit.
+fn toolchain_labels(target_dir: &TargetDir, toolchains: &Toolchains) -> HashMap<String, String> {
+    let mut labels = HashMap::new();
+    for profile in target_dir.profiles() {
+        match profile.units() {
+            Ok(units) => labels.extend(
+                units
+                    .into_iter()
+                    .map(|unit| (unit.hash, toolchains.label_of(unit.rustc))),
+            ),
+            Err(e) => warn!("{:?}", e),
+        }
+    }
+    labels
+}
+
+/// The space freed from units built by toolchains that weren't kept, by toolchain, largest first.
+fn swept_by_toolchain(swept: &SweepPlan, labels: &HashMap<String, String>) -> Vec<(String, u64)> {
+    let mut sizes: HashMap<&str, u64> = HashMap::new();
+    for entry in swept.entries() {
+        if entry.reason == Reason::Toolchain {
+            let label = labels.get(&entry.hash).map_or("unknown", String::as_str);
+            *sizes.entry(label).or_default() += entry.size;
+        }
+    }
+    let mut sizes: Vec<(String, u64)> = sizes
+        .into_iter()
+        .map(|(label, size)| (label.to_owned(), size))
+        .collect();
+    sizes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    sizes
+}
+
+/// Starts a run in the quarantine given with `--quarantine`, unless this is a dry run.
+fn begin_quarantine(args: &Args, dry_run: bool) -> anyhow::Result<Option<QuarantineRun>> {
+    match &args.quarantine {
+        Some(dir) if !dry_run => Ok(Some(Quarantine::new(dir).begin_run()?)),
+        _ => Ok(None),
+    }
+}
+
+/// The quarantine given with `--quarantine`, which the `restore` and `purge` commands need.
+fn quarantine(args: &Args) -> anyhow::Result<Quarantine> {
+    match &args.quarantine {
+        Some(dir) => Ok(Quarantine::new(dir)),
+        None => bail!("The quarantine folder must be given with --quarantine"),
+    }
+}
+
+/// Moves the artifacts of a quarantined sweep back where they were.
+fn restore(args: &Args, run: Option<&str>) -> anyhow::Result<()> {
+    let run = quarantine(args)?.run(run)?;
+    let plan: SweepPlan = run
+        .entries()?
+        .into_iter()
+        .map(|quarantined| quarantined.entry)
+        .collect();
+    if args.dry_run {
+        for entry in plan.entries() {
+            debug!("Would restore: {:?}", &entry.path);
+        }
+        info!(
+            "Would restore: {} from {:?}",
+            format_bytes_or_nothing(plan.total_size()),
+            run.path()
+        );
+        return Ok(());
+    }
+
+    let wait = args.on_locked.unwrap_or_default() == OnLocked::Wait;
+    let Some(_lock) = TargetLock::lock_plan(&plan, wait)? else {
+        warn!(
+            "Skipping {:?} as a build or another sweep is using its target folders",
+            run.path()
+        );
+        return Ok(());
     };
+    let run_path = run.path().to_owned();
+    let restored = run.restore()?;
+    info!(
+        "Restored {} from {run_path:?}",
+        format_bytes_or_nothing(restored.total_size())
+    );
+    Ok(())
+}
 
-    MetadataCommand::new()
-        .manifest_path(manifest_path)
-        .no_deps()
-        .exec()
+/// Removes the quarantined sweeps older than the given number of days for good.
+fn purge(args: &Args, days: Option<u64>) -> anyhow::Result<()> {
+    let quarantine = quarantine(args)?;
+    let older_than = Duration::from_secs(days.unwrap_or(0) * 24 * 3600);
+    if args.dry_run {
+        let runs = quarantine.runs_older_than(older_than)?;
+        for run in &runs {
+            debug!("Would purge: {:?}", run.path());
+        }
+        let size = runs.iter().map(QuarantineRun::size).sum();
+        info!(
+            "Would purge: {} from {:?}",
+            format_bytes_or_nothing(size),
+            quarantine.path()
+        );
+    } else {
+        let freed = quarantine.purge(older_than)?;
+        info!(
+            "Purged {} from {:?}",
+            format_bytes_or_nothing(freed),
+            quarantine.path()
+        );
+    }
+    Ok(())
 }
 
-fn main() -> anyhow::Result<()> {
-    let args = cli::parse();
+/// Locks the target folders against builds and other sweeps, waiting or skipping the locked ones
+/// according to `--on-locked`. A dry run removes nothing, so it doesn't lock anything either.
+/// Returns the target folders that can be swept, and the locks to hold until they are.
+fn lock_target_dirs(
+    target_dirs: Vec<TargetDir>,
+    dry_run: bool,
+    on_locked: OnLocked,
+) -> (Vec<TargetDir>, Vec<TargetLock>) {
+    if dry_run {
+        return (target_dirs, vec![]);
+    }
+    let mut locked = Vec::with_capacity(target_dirs.len());
+    let mut locks = Vec::with_capacity(target_dirs.len());
+    for target_dir in target_dirs {
+        match TargetLock::lock(&target_dir, on_locked == OnLocked::Wait) {
+            Ok(Some(lock)) => {
+                locked.push(target_dir);
+                locks.push(lock);
+            }
+            Ok(None) => warn!(
+                "Skipping {:?} as a build or another sweep is using it",
+                target_dir.path()
+            ),
+            Err(e) => error!(
+                "{:?}",
+                e.context(format!("Failed to clean {:?}", target_dir.path()))
+            ),
+        }
+    }
+    (locked, locks)
+}
 
-    let criterion = args.criterion()?;
+/// Executes a plan file written by `--plan-out`, skipping everything that changed since.
+fn apply(args: &Args, plan_file: &Path) -> anyhow::Result<()> {
     let dry_run = args.dry_run;
-    setup_logging(args.verbose);
+    let on_locked = args.on_locked.unwrap_or_default();
+    let mut plan = SweepPlan::load(plan_file)?;
+    let _lock = if dry_run {
+        None
+    } else {
+        let lock = TargetLock::lock_plan(&plan, on_locked == OnLocked::Wait)?;
+        if lock.is_none() {
+            warn!("Skipping {plan_file:?} as a build or another sweep is using its target folders");
+            return Ok(());
+        }
+        lock
+    };
+    plan.skip_stale();
 
-    // Default to current invocation path.
-    let paths = match args.path.len() {
-        0 => vec![env::current_dir().expect("Failed to get current directory")],
-        _ => args.path,
+    // Each target folder is reported on its own, like when the plan was made.
+    let mut by_target_dir: BTreeMap<PathBuf, SweepPlan> = BTreeMap::new();
+    for entry in plan.entries() {
+        let target_dir = entry
+            .target_dir
+            .clone()
+            .unwrap_or_else(|| plan_file.to_owned());
+        by_target_dir
+            .entry(target_dir)
+            .or_default()
+            .push(entry.clone());
+    }
+    let mut sweeper = Sweeper {
+        dry_run,
+        message_format: args.message_format.unwrap_or_default(),
+        exclude: vec![],
+        planned: None,
+        quarantine: begin_quarantine(args, dry_run)?,
+        toolchains: None,
+        total_cleaned: 0,
     };
+    for (target_dir, plan) in by_target_dir {
+        sweeper.sweep_project(&TargetDir::new(target_dir), plan);
+    }
+    sweeper.finish(None)
+}
 
-    // FIXME: Change to write to every passed in path instead of just the first one
-    if let Criterion::Stamp = criterion {
-        if paths.len() > 1 {
-            anyhow::bail!("Using multiple paths and --stamp is currently unsupported");
+/// Sweeps the target folder of a project down to the dependency artifacts worth caching in CI,
+/// removing everything that wasn't used since the project was stamped at the start of the job.
+fn ci_prepare(args: &Args, path: &Path) -> anyhow::Result<()> {
+    let dry_run = args.dry_run;
+    let project = metadata(path).context(format!(
+        "Failed to gather metadata for {:?}",
+        path.display()
+    ))?;
+    let members = workspace_members(&[path.to_owned()])?;
+    let started = Timestamp::load(path, dry_run)
+        .context("Run `cargo sweep --stamp` at the start of the job")?;
+    let criteria = [
+        SweepCriterion::NotCacheable(members),
+        SweepCriterion::OlderThan(Duration::from(started)),
+    ];
+
+    let on_locked = args.on_locked.unwrap_or_default();
+    let (target_dirs, _locks) = lock_target_dirs(vec![project.target_dir()], dry_run, on_locked);
+    let mut sweeper = Sweeper {
+        dry_run,
+        message_format: args.message_format.unwrap_or_default(),
+        exclude: args.exclude.clone(),
+        planned: None,
+        quarantine: begin_quarantine(args, dry_run)?,
+        toolchains: None,
+        total_cleaned: 0,
+    };
+    for (target_dir, plan) in target_dirs
+        .iter()
+        .zip(plan_sweep_all(&target_dirs, &criteria))
+    {
+        let plan = plan.context(format!("Failed to clean {:?}", target_dir.path()))?;
+        sweeper.sweep_project(target_dir, plan);
+    }
+    sweeper.finish(None)
+}
+
+/// Runs a command, then sweeps every unit in the target folders of the given paths it didn't use.
+/// Returns the exit status of the command, so that it can be passed on.
+fn exec(
+    args: &Args,
+    paths: &[PathBuf],
+    command: &[OsString],
+    sweep_on_failure: bool,
+) -> anyhow::Result<ExitStatus> {
+    let dry_run = args.dry_run;
+    let (program, arguments) = command.split_first().context("No command given")?;
+    let started = Timestamp::new();
+    debug!("Running {command:?}");
+    let status = std::process::Command::new(program)
+        .args(arguments)
+        .status()
+        .context(format!("Failed to run {program:?}"))?;
+    if !status.success() && !sweep_on_failure {
+        warn!("Skipping the sweep as {program:?} failed with {status}");
+        return Ok(status);
+    }
+
+    // Looked up once the command ran, as it may have created the target folder.
+    let target_dirs = find_target_dirs(paths, args.recursive, args.hidden)?;
+    let criteria = [SweepCriterion::OlderThan(Duration::from(started))];
+    let on_locked = args.on_locked.unwrap_or_default();
+    let (target_dirs, _locks) = lock_target_dirs(target_dirs, dry_run, on_locked);
+    let mut sweeper = Sweeper {
+        dry_run,
+        message_format: args.message_format.unwrap_or_default(),
+        exclude: args.exclude.clone(),
+        planned: None,
+        quarantine: begin_quarantine(args, dry_run)?,
+        toolchains: None,
+        total_cleaned: 0,
+    };
+    for (target_dir, plan) in target_dirs
+        .iter()
+        .zip(plan_sweep_all(&target_dirs, &criteria))
+    {
+        match plan {
+            Ok(plan) => {
+                sweeper.sweep_project(target_dir, plan);
+            }
+            Err(e) => error!(
+                "{:?}",
+                e.context(format!("Failed to clean {:?}", target_dir.path()))
+            ),
         }
+    }
+    sweeper.finish(None)?;
+    Ok(status)
+}
 
-        debug!("Writing timestamp file in: {:?}", paths[0]);
-        return Timestamp::new()
-            .store(paths[0].as_path())
-            .context("Failed to write timestamp file");
+/// The roots of the workspaces at the given paths, or of all workspaces below them with `recursive`,
+/// including every workspace sharing a target folder with another. Paths that aren't Cargo projects are left out.
+fn find_workspaces(paths: &[PathBuf], recursive: bool, include_hidden: bool) -> Vec<PathBuf> {
+    let projects: Vec<ProjectMetadata> = if recursive {
+        info!("Searching recursively for Rust workspaces");
+        paths
+            .iter()
+            .flat_map(|path| find_cargo_projects(path, include_hidden))
+            .collect()
+    } else {
+        paths
+            .iter()
+            .filter_map(|path| metadata(path).ok())
+            .collect()
     };
+    let mut workspaces = vec![];
+    for project in projects {
+        if !workspaces.contains(&project.metadata.workspace_root) {
+            workspaces.push(project.metadata.workspace_root);
+        }
+    }
+    workspaces
+}
 
-    let processed_paths = if args.recursive {
+/// The packages the given workspaces can depend on, see `--unreferenced`.
+fn dependency_graph(workspaces: &[PathBuf]) -> anyhow::Result<DependencyGraph> {
+    let mut graph = DependencyGraph::new();
+    for workspace in workspaces {
+        let metadata = dependency_metadata(workspace).context(format!(
+            "Failed to resolve the dependencies of {workspace:?}"
+        ))?;
+        for package in metadata.metadata.packages {
+            if let Some(dir) = package.manifest_path.parent() {
+                graph.add_package(package.name, dir);
+            }
+        }
+    }
+    Ok(graph)
+}
+
+/// The names of the members of the given workspaces, see `--only-members`, `--only-deps` and `ci-prepare`.
+fn workspace_members(workspaces: &[PathBuf]) -> anyhow::Result<HashSet<String>> {
+    let mut members = HashSet::new();
+    for workspace in workspaces {
+        let metadata = metadata(workspace)
+            .context(format!("Failed to gather the members of {workspace:?}"))?;
+        // Without dependencies, the packages are exactly the members.
+        members.extend(
+            metadata
+                .metadata
+                .packages
+                .into_iter()
+                .map(|package| package.name),
+        );
+    }
+    Ok(members)
+}
+
+/// The target folders of the projects at the given paths, or of all projects below them with `recursive`.
+fn find_target_dirs(
+    paths: &[PathBuf],
+    recursive: bool,
+    include_hidden: bool,
+) -> anyhow::Result<Vec<TargetDir>> {
+    let target_dirs = if recursive {
         info!("Searching recursively for Rust project folders");
         paths
             .iter()
-            .flat_map(|path| find_cargo_projects(path, args.hidden))
-            .collect::<Vec<_>>()
+            .flat_map(|path| find_cargo_projects(path, include_hidden))
+            .map(|project| project.target_dir())
+            .fold(vec![], |mut target_dirs, target_dir| {
+                if !target_dirs.contains(&target_dir) {
+                    target_dirs.push(target_dir);
+                }
+                target_dirs
+            })
     } else {
         let mut return_paths = Vec::with_capacity(paths.len());
-        for path in &paths {
+        for path in paths {
             let metadata = metadata(path).context(format!(
                 "Failed to gather metadata for {:?}",
                 path.display()
             ))?;
-            let out = Path::new(&metadata.target_directory).to_path_buf();
-            if out.exists() {
-                return_paths.push(out);
+            let target_dir = metadata.target_dir();
+            if target_dir.path().exists() || target_dir.build_dir().exists() {
+                return_paths.push(target_dir);
             } else {
-                warn!("Failed to clean {:?} as it does not exist.", out)
+                warn!("Skipping {:?} as it does not exist.", target_dir.path())
             };
         }
         return_paths
     };
+    Ok(target_dirs)
+}
 
-    let mut total_cleaned = 0;
+/// The toolchains the units in the target folders may have been built by. These are stored in the
+/// Cargo home directory, so that toolchains are still known by their version once uninstalled.
+fn load_toolchains(target_dirs: &[TargetDir]) -> Toolchains {
+    let path = Toolchains::default_path();
+    let mut toolchains = path
+        .as_deref()
+        .map(Toolchains::load)
+        .transpose()
+        .unwrap_or_else(|e| {
+            warn!("{:?}", e.context("Failed to load the known toolchains"));
+            None
+        })
+        .unwrap_or_default();
+    toolchains.add_installed();
+    for target_dir in target_dirs {
+        if let Err(e) = toolchains.add_rustc_info(target_dir) {
+            warn!("{:?}", e);
+        }
+    }
+    if let Some(path) = &path {
+        if let Err(e) = toolchains.store(path) {
+            warn!("{:?}", e.context("Failed to store the known toolchains"));
+        }
+    }
+    toolchains
+}
 
-    // `None`: do not remove based on toolchain version
-    // `Some(None)`: remove all installed toolchains
-    // `Some(Some(Vec))`: remove only the specified toolchains
-    let toolchains = match &criterion {
-        Criterion::Installed => Some(None),
-        Criterion::Toolchains(vec) => Some(Some(vec.clone())),
-        _ => None,
-    };
-    if let Some(toolchains) = toolchains {
-        let hashed_toolchains = match hash_toolchains(toolchains.as_ref()) {
-            Ok(toolchains) => toolchains,
-            Err(err) => {
-                error!("{:?}", err.context("Failed to load toolchains."));
-                return Ok(());
-            }
-        };
+/// The number of groups listed in every table of a report, the rest are summed up in a last row.
+const REPORT_ROWS: usize = 20;
 
-        for project_path in &processed_paths {
-            match remove_not_built_with(project_path, &hashed_toolchains, dry_run) {
-                Ok(cleaned_amount) if dry_run => {
-                    info!(
-                        "Would clean: {} from {project_path:?}",
-                        format_bytes_or_nothing(cleaned_amount)
-                    );
-                    total_cleaned += cleaned_amount;
-                }
-                Ok(cleaned_amount) => {
-                    info!(
-                        "Cleaned {} from {project_path:?}",
-                        format_bytes_or_nothing(cleaned_amount)
-                    );
-                    total_cleaned += cleaned_amount;
-                }
-                Err(e) => error!(
-                    "{:?}",
-                    e.context(format!("Failed to clean {project_path:?}"))
-                ),
-            };
+/// Prints where the space of every target folder goes, without removing anything.
+fn report(
+    paths: &[PathBuf],
+    recursive: bool,
+    include_hidden: bool,
+    message_format: MessageFormat,
+) -> anyhow::Result<()> {
+    let target_dirs = find_target_dirs(paths, recursive, include_hidden)?;
+    let toolchains = load_toolchains(&target_dirs);
+    for target_dir in &target_dirs {
+        let report = Report::new(target_dir, &toolchains)
+            .context(format!("Failed to report on {:?}", target_dir.path()))?;
+        match message_format {
+            MessageFormat::Human => print_report(&report),
+            MessageFormat::Json => Message::Report { report: &report }.emit(),
         }
-    } else if let Criterion::MaxSize(size) = criterion {
-        for project_path in &processed_paths {
-            match remove_older_until_fits(project_path, size, dry_run) {
-                Ok(cleaned_amount) if dry_run => {
-                    info!(
-                        "Would clean: {} from {project_path:?}",
-                        format_bytes_or_nothing(cleaned_amount)
-                    );
-                    total_cleaned += cleaned_amount;
-                }
-                Ok(cleaned_amount) => {
-                    info!(
-                        "Cleaned {} from {project_path:?}",
-                        format_bytes_or_nothing(cleaned_amount)
-                    );
-                    total_cleaned += cleaned_amount;
-                }
-                Err(e) => error!("Failed to clean {:?}: {:?}", project_path, e),
+    }
+    Ok(())
+}
+
+fn print_report(report: &Report) {
+    println!(
+        "{}: {}, {} of it in units",
+        report.path.display(),
+        format_bytes(report.size),
+        format_bytes(report.units_size)
+    );
+    let tables = [
+        ("Profile", &report.by_profile),
+        ("Target", &report.by_target),
+        ("Crate", &report.by_crate),
+        ("Toolchain", &report.by_toolchain),
+        ("Last used", &report.by_age),
+        ("Untracked", &report.untracked),
+    ];
+    for (title, usages) in tables {
+        println!();
+        println!("  {title:<40} {:>12} {:>6}", "Size", "Units");
+        let print_row = |name: &str, size: u64, units: usize| {
+            // Untracked files don't belong to any unit.
+            let units = if units > 0 {
+                units.to_string()
+            } else {
+                String::new()
             };
+            println!("  {name:<40} {:>12} {units:>6}", format_bytes(size));
+        };
+        for usage in usages.iter().take(REPORT_ROWS) {
+            print_row(&usage.name, usage.size, usage.units);
+        }
+        if let Some(rest) = usages.get(REPORT_ROWS..).filter(|rest| !rest.is_empty()) {
+            print_row(
+                &format!("{} more", rest.len()),
+                rest.iter().map(|usage| usage.size).sum(),
+                rest.iter().map(|usage| usage.units).sum(),
+            );
         }
+    }
+    println!();
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut args = cli::parse();
+    args.check_command()?;
+
+    if args.build_dir_root.is_some() && args.recursive && args.path.is_empty() {
+        // Every workspace that isn't found counts as gone, which shouldn't depend on where this is run.
+        bail!(
+            "--build-dir-root with --recursive needs the paths holding every workspace to be given"
+        );
+    }
+
+    // Default to current invocation path.
+    let paths = match args.path.len() {
+        0 => vec![env::current_dir().expect("Failed to get current directory")],
+        _ => std::mem::take(&mut args.path),
+    };
+
+    // Like the stamp file, the configuration is taken from the first path swept or reported on.
+    // Commands working on plans or the quarantine take no criteria, so they don't need one.
+    let config_path = match &args.command {
+        Some(Command::Apply { .. } | Command::Restore { .. } | Command::Purge { .. }) => None,
+        Some(Command::Report { path }) => Some(path.first().unwrap_or(&paths[0]).clone()),
+        Some(Command::CiPrepare { path }) => Some(path.clone().unwrap_or_else(|| paths[0].clone())),
+        Some(Command::Exec { .. }) | None => Some(paths[0].clone()),
+    };
+    let args = match config_path {
+        Some(config_path) => {
+            let metadata = metadata(&config_path).ok();
+            let config = Config::load(
+                &config_path,
+                metadata.as_ref().map(|project| &project.metadata),
+            )?;
+            args.with_config(config)?
+        }
+        None => args,
+    };
+    let message_format = args.message_format.unwrap_or_default();
+    let on_locked = args.on_locked.unwrap_or_default();
+    setup_logging(args.verbose, message_format);
+
+    match &args.command {
+        Some(Command::Apply { plan }) => return apply(&args, plan),
+        Some(Command::Restore { run }) => return restore(&args, run.as_deref()),
+        Some(Command::Purge { time }) => return purge(&args, *time),
+        Some(Command::Report { path }) => {
+            let paths = if path.is_empty() { &paths } else { path };
+            return report(paths, args.recursive, args.hidden, message_format);
+        }
+        Some(Command::CiPrepare { path }) => {
+            let path = path.as_deref().unwrap_or(&paths[0]);
+            return ci_prepare(&args, path);
+        }
+        Some(Command::Exec {
+            sweep_on_failure,
+            command,
+        }) => {
+            let status = exec(&args, &paths, command, *sweep_on_failure)?;
+            if !status.success() {
+                // Fail like the command did, so that it can stand in for it.
+                std::process::exit(status.code().unwrap_or(1));
+            }
+            return Ok(());
+        }
+        None => {}
+    }
+
+    let dry_run = args.dry_run || args.plan_out.is_some();
+    let mut sweeper = Sweeper {
+        dry_run,
+        message_format,
+        exclude: args.exclude.clone(),
+        planned: args.plan_out.as_ref().map(|_| SweepPlan::new()),
+        quarantine: begin_quarantine(&args, dry_run)?,
+        toolchains: None,
+        total_cleaned: 0,
+    };
+
+    if let Some(root) = &args.build_dir_root {
+        // The paths are only used to recognize workspaces, so they don't have to be Cargo projects.
+        let workspaces = find_workspaces(&paths, args.recursive, args.hidden);
+        let keep_duration = args
+            .keep_days()
+            .map(|days| Duration::from_secs(days * 24 * 3600));
+        // Only a recursive search is thorough enough to tell that a workspace is gone.
+        let (roots, _locks) = lock_target_dirs(vec![TargetDir::new(root)], dry_run, on_locked);
+        for root in &roots {
+            let plan =
+                plan_sweep_build_root(root.path(), &workspaces, args.recursive, keep_duration)
+                    .context(format!("Failed to clean {:?}", root.path()))?;
+            sweeper.sweep_project(root, plan);
+        }
+        return sweeper.finish(args.plan_out.as_deref());
+    }
+
+    // Interactively, the criteria only preselect units, so none are needed.
+    let criteria = if args.interactive && !args.has_criterion() {
+        vec![]
     } else {
-        let keep_duration = if let Criterion::File = criterion {
-            let ts = Timestamp::load(paths[0].as_path(), dry_run)?;
-            Duration::from(ts)
-        } else if let Criterion::Time(days_to_keep) = criterion {
-            Duration::from_secs(days_to_keep * 24 * 3600)
-        } else {
-            unreachable!("unknown criteria {:?}", criterion);
-        };
+        args.criteria()?
+    };
+    if args.interactive && !std::io::stdout().is_tty() {
+        bail!("--interactive needs a terminal");
+    }
 
-        for project_path in &processed_paths {
-            match remove_older_than(project_path, &keep_duration, dry_run) {
-                Ok(cleaned_amount) if dry_run => {
-                    info!(
-                        "Would clean: {} from {project_path:?}",
-                        format_bytes_or_nothing(cleaned_amount)
-                    );
-                    total_cleaned += cleaned_amount;
-                }
-                Ok(cleaned_amount) => {
-                    info!(
-                        "Cleaned {} from {project_path:?}",
-                        format_bytes_or_nothing(cleaned_amount)
-                    );
-                    total_cleaned += cleaned_amount;
-                }
-                Err(e) => error!("Failed to clean {:?}: {:?}", project_path, e),
-            };
+    // FIXME: Change to write to every passed in path instead of just the first one
+    if let [Criterion::Stamp] = criteria[..] {
+        if paths.len() > 1 {
+            anyhow::bail!("Using multiple paths and --stamp is currently unsupported");
         }
 
-        if processed_paths.len() > 1 {
-            info!("Total amount: {}", format_bytes(total_cleaned));
+        debug!("Writing timestamp file in: {:?}", paths[0]);
+        return Timestamp::new()
+            .store(paths[0].as_path())
+            .context("Failed to write timestamp file");
+    };
+
+    let processed_paths = find_target_dirs(&paths, args.recursive, args.hidden)?;
+
+    let mut sweep_criteria = vec![];
+    for criterion in criteria {
+        sweep_criteria.push(match criterion {
+            Criterion::Stamp => unreachable!("--stamp can't be combined with other criteria"),
+            // `None`: remove all installed toolchains
+            // `Some(Vec)`: remove only the specified toolchains
+            Criterion::Installed | Criterion::Toolchains(_) => {
+                let toolchains = match &criterion {
+                    Criterion::Toolchains(vec) => Some(vec),
+                    _ => None,
+                };
+                match hash_toolchains(toolchains) {
+                    Ok(toolchains) => {
+                        sweeper.toolchains = Some(load_toolchains(&processed_paths));
+                        SweepCriterion::NotBuiltWith(toolchains)
+                    }
+                    Err(err) => {
+                        error!("{:?}", err.context("Failed to load toolchains."));
+                        return Ok(());
+                    }
+                }
+            }
+            Criterion::File => {
+                let ts = Timestamp::load(paths[0].as_path(), dry_run)?;
+                SweepCriterion::OlderThan(Duration::from(ts))
+            }
+            Criterion::Time(days_to_keep) => {
+                SweepCriterion::OlderThan(Duration::from_secs(days_to_keep * 24 * 3600))
+            }
+            Criterion::Unreferenced => {
+                let workspaces = find_workspaces(&paths, args.recursive, args.hidden);
+                SweepCriterion::Unreferenced(dependency_graph(&workspaces)?)
+            }
+            Criterion::KeepLatest(count) => SweepCriterion::KeepLatest(count),
+            Criterion::MaxSize(size) => SweepCriterion::MaxSize(size),
+            Criterion::TotalMaxSize(size) => SweepCriterion::TotalMaxSize(size),
+            Criterion::EnsureFree(free) => SweepCriterion::EnsureFree(free),
+        });
+    }
+
+    let scope = if args.only_members || args.only_deps {
+        let workspaces = find_workspaces(&paths, args.recursive, args.hidden);
+        let members = workspace_members(&workspaces)?;
+        if args.only_members {
+            Scope::Only(members)
+        } else {
+            Scope::Except(members)
         }
+    } else {
+        Scope::All
+    };
+
+    let (processed_paths, plans, _locks) = if args.interactive {
+        let preselected = plan_sweep_scoped(&processed_paths, &sweep_criteria, &scope);
+        let Some(selection) = interactive::choose(
+            &processed_paths,
+            &preselected,
+            &load_toolchains(&processed_paths),
+        )?
+        else {
+            info!("Nothing was swept");
+            return Ok(());
+        };
+        // Only locked once chosen, so that builds don't wait for the user.
+        let target_dirs = selection.iter().map(|(target_dir, _)| target_dir.clone());
+        let (processed_paths, locks) = lock_target_dirs(target_dirs.collect(), dry_run, on_locked);
+        let plans = processed_paths
+            .iter()
+            .map(|target_dir| {
+                let (_, hashes) = selection
+                    .iter()
+                    .find(|(selected, _)| selected == target_dir)
+                    .expect("only selected target folders are locked");
+                plan_selected(target_dir, hashes)
+            })
+            .collect();
+        (processed_paths, plans, locks)
+    } else {
+        // The target folders stay locked until the end of the sweep.
+        let (processed_paths, locks) = lock_target_dirs(processed_paths, dry_run, on_locked);
+        let plans = plan_sweep_scoped(&processed_paths, &sweep_criteria, &scope);
+        (processed_paths, plans, locks)
+    };
+    for (target_dir, plan) in processed_paths.iter().zip(plans) {
+        match plan {
+            Ok(plan) => {
+                sweeper.sweep_project(target_dir, plan);
+            }
+            Err(e) => error!(
+                "{:?}",
+                e.context(format!("Failed to clean {:?}", target_dir.path()))
+            ),
+        };
     }
 
-    Ok(())
+    if processed_paths.len() > 1 {
+        info!("Total amount: {}", format_bytes(sweeper.total_cleaned));
+    }
+
+    sweeper.finish(args.plan_out.as_deref())
 }
@@ -0,0 +1,38 @@
+use cargo_sweep::{PlanEntry, Report};
+use serde_derive::Serialize;
+use std::path::Path;
+
+/// A single line of output for `--message-format json`.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Message<'a> {
+    /// A file or directory that was removed, or would be removed on a dry run.
+    Artifact {
+        dry_run: bool,
+        #[serde(flatten)]
+        entry: &'a PlanEntry,
+    },
+    /// The amount of bytes cleaned from a single target directory.
+    Project {
+        dry_run: bool,
+        path: &'a Path,
+        cleaned: u64,
+    },
+    /// The amount of bytes cleaned from all target directories.
+    Total { dry_run: bool, cleaned: u64 },
+    /// Where the space of a target directory goes, see `cargo sweep report`.
+    Report {
+        #[serde(flatten)]
+        report: &'a Report,
+    },
+}
+
+impl Message<'_> {
+    /// Prints the message as a single line of JSON to stdout.
+    pub fn emit(&self) {
+        println!(
+            "{}",
+            serde_json::to_string(self).expect("messages are always serializable")
+        );
+    }
+}
@@ -0,0 +1,419 @@
+use anyhow::{Context, Error};
+use log::{debug, warn};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{from_str, to_string_pretty};
+use std::{
+    collections::HashSet,
+    fs::{self, remove_dir_all, remove_file},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use walkdir::WalkDir;
+
+use crate::unreferenced::DependencyGraph;
+
+/// Why an entry was selected for removal.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Reason {
+    /// The unit was not built by any of the toolchains that should be kept.
+    Toolchain,
+    /// The unit was not used within the given duration.
+    OlderThan(Duration),
+    /// The unit belongs to a package, or a version of it, that no workspace depends on anymore.
+    Unreferenced,
+    /// The unit was older than the given number of more recently used builds of the same crate.
+    KeepLatest(u64),
+    /// The unit or incremental session is of no use to later builds restored from a cache.
+    NotCacheable,
+    /// The unit was among the least recently used while the target directory exceeded the given size in bytes.
+    MaxSize(u64),
+    /// The unit was among the least recently used while all swept target directories together
+    /// exceeded the given size in bytes.
+    TotalMaxSize(u64),
+    /// The unit was among the least recently used while the filesystem had less free space than requested.
+    EnsureFree(FreeSpace),
+    /// The incremental compilation session was replaced by a newer one for the same crate.
+    Superseded,
+    /// The build directory in a shared build directory root doesn't belong to any known workspace,
+    /// so its workspace was moved or deleted.
+    WorkspaceGone,
+    /// The unit was chosen for removal by hand.
+    Selected,
+}
+
+/// An amount of free space to ensure on a filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FreeSpace {
+    Bytes(u64),
+    /// A percentage of the total size of the filesystem, from 0 to 100.
+    Percent(u8),
+}
+
+impl FreeSpace {
+    /// The amount of free bytes, given the total size of the filesystem in bytes.
+    pub fn bytes(self, total_space: u64) -> u64 {
+        match self {
+            FreeSpace::Bytes(bytes) => bytes,
+            FreeSpace::Percent(percent) => total_space / 100 * u64::from(percent),
+        }
+    }
+}
+
+/// A rule selecting units to remove from a target directory, see [crate::plan_sweep].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Criterion {
+    /// Remove units that were not built by one of the given rustc versions, see [crate::hash_toolchains].
+    NotBuiltWith(HashSet<u64>),
+    /// Remove units of packages, or versions of them, that aren't in the dependency graph anymore.
+    /// Units that can't be told apart by version are removed only once the package is gone altogether.
+    Unreferenced(DependencyGraph),
+    /// Remove units that were not used within the given duration.
+    OlderThan(Duration),
+    /// Remove all but the given number of most recently used builds of every crate in each profile.
+    /// Builds of its library, build script, tests and binaries are counted separately.
+    KeepLatest(u64),
+    /// Remove what a CI cache of the target directory would be of no use for: the units of the
+    /// given workspace members, which change with every commit, test and example builds of any
+    /// package, and incremental sessions.
+    NotCacheable(HashSet<String>),
+    /// Remove the least recently used units until the target directory is no larger than the given size in bytes.
+    MaxSize(u64),
+    /// Remove the least recently used units of all swept target directories until they are
+    /// no larger than the given size in bytes together, see [crate::plan_sweep_all].
+    TotalMaxSize(u64),
+    /// Remove the least recently used units until the filesystem holding the target directory
+    /// has the given amount of free space, or nothing is left to remove.
+    /// Target directories on the same filesystem share its free space.
+    EnsureFree(FreeSpace),
+}
+
+impl Criterion {
+    /// The reason recorded for units removed by this criterion.
+    pub fn reason(&self) -> Reason {
+        match self {
+            Criterion::NotBuiltWith(_) => Reason::Toolchain,
+            Criterion::Unreferenced(_) => Reason::Unreferenced,
+            Criterion::OlderThan(duration) => Reason::OlderThan(*duration),
+            Criterion::KeepLatest(count) => Reason::KeepLatest(*count),
+            Criterion::NotCacheable(_) => Reason::NotCacheable,
+            Criterion::MaxSize(size) => Reason::MaxSize(*size),
+            Criterion::TotalMaxSize(size) => Reason::TotalMaxSize(*size),
+            Criterion::EnsureFree(free) => Reason::EnsureFree(*free),
+        }
+    }
+}
+
+/// Which units a sweep may remove, by the package they belong to, see [crate::plan_sweep_scoped].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Scope {
+    #[default]
+    All,
+    /// Only the units of the named packages, like the members of a workspace.
+    Only(HashSet<String>),
+    /// Every unit except those of the named packages.
+    Except(HashSet<String>),
+}
+
+impl Scope {
+    /// Whether the units of the named package may be removed.
+    /// Like Cargo, `-` and `_` are interchangeable, so this matches incremental sessions as well.
+    pub fn includes(&self, package: &str) -> bool {
+        match self {
+            Scope::All => true,
+            Scope::Only(packages) => is_named(packages, package),
+            Scope::Except(packages) => !is_named(packages, package),
+        }
+    }
+}
+
+/// Whether the package is one of the named packages, taking `-` and `_` to be the same like Cargo does.
+pub(crate) fn is_named(packages: &HashSet<String>, package: &str) -> bool {
+    let package = package.replace('-', "_");
+    packages
+        .iter()
+        .any(|name| name.replace('-', "_") == package)
+}
+
+/// A single file or directory that is planned to be removed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlanEntry {
+    pub path: PathBuf,
+    /// Hash of the unit this file or directory belongs to.
+    pub hash: String,
+    /// Space in bytes freed on disk by removing it, for directories this includes all files inside it.
+    pub size: u64,
+    pub reason: Reason,
+    /// Latest modification time of the file, or of any file inside the directory, when the plan was made.
+    pub modified: Option<SystemTime>,
+    /// Latest access time of the file, or of any file inside the directory, when the plan was made.
+    pub accessed: Option<SystemTime>,
+    /// Target folder the entry was planned for, recorded in plans written with `--plan-out`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_dir: Option<PathBuf>,
+}
+
+impl PlanEntry {
+    /// Creates an entry, recording the current modification and access times of `path`.
+    pub fn new(path: PathBuf, hash: String, size: u64, reason: Reason) -> Self {
+        let (modified, accessed) = latest_file_times(&path);
+        Self {
+            path,
+            hash,
+            size,
+            reason,
+            modified,
+            accessed,
+            target_dir: None,
+        }
+    }
+
+    /// Whether the file, or any file inside the directory, was modified or accessed since the entry was created.
+    pub fn is_stale(&self) -> bool {
+        let (modified, accessed) = latest_file_times(&self.path);
+        modified > self.modified || accessed > self.accessed
+    }
+}
+
+/// The latest modification and access time of a file, or of any file inside a directory.
+/// Directories themselves are ignored, as listing them already updates their access time.
+fn latest_file_times(path: &Path) -> (Option<SystemTime>, Option<SystemTime>) {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .fold((None, None), |(modified, accessed), metadata| {
+            (
+                modified.max(metadata.modified().ok()),
+                accessed.max(metadata.accessed().ok()),
+            )
+        })
+}
+
+/// A list of files and directories to remove from a target directory.
+///
+/// Creating a plan does not touch the file system besides reading it, nothing is removed
+/// until [SweepPlan::execute] is called. Entries can be inspected, filtered or merged before that,
+/// or the plan can be stored to be reviewed and applied later.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SweepPlan {
+    entries: Vec<PlanEntry>,
+}
+
+impl SweepPlan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, entry: PlanEntry) {
+        self.entries.push(entry);
+    }
+
+    pub fn entries(&self) -> &[PlanEntry] {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The total amount of bytes that would be freed by executing this plan.
+    pub fn total_size(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.size).sum()
+    }
+
+    /// Keeps only the entries for which the predicate returns `true`, vetoing the others.
+    pub fn retain(&mut self, f: impl FnMut(&PlanEntry) -> bool) {
+        self.entries.retain(f);
+    }
+
+    /// Appends all entries of `other` which are not already part of this plan.
+    pub fn merge(&mut self, other: SweepPlan) {
+        let mut seen: HashSet<PathBuf> = self.entries.iter().map(|e| e.path.clone()).collect();
+        for entry in other.entries {
+            if seen.insert(entry.path.clone()) {
+                self.entries.push(entry);
+            }
+        }
+    }
+
+    /// Records the target folder every entry was planned for.
+    pub fn set_target_dir(&mut self, target_dir: &Path) {
+        for entry in &mut self.entries {
+            entry.target_dir = Some(target_dir.to_owned());
+        }
+    }
+
+    /// Drops every entry that no longer exists or changed since the plan was made.
+    pub fn skip_stale(&mut self) {
+        self.entries.retain(|entry| {
+            if !entry.path.exists() {
+                debug!("Skipping {:?} as it no longer exists", &entry.path);
+                false
+            } else if entry.is_stale() {
+                warn!(
+                    "Skipping {:?} as it was used since the plan was made",
+                    &entry.path
+                );
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Attempts to store the plan as JSON at the given path.
+    pub fn store(&self, path: &Path) -> Result<(), Error> {
+        fs::write(path, to_string_pretty(&self)?)
+            .context(format!("failed to write plan file {}", path.display()))
+    }
+
+    /// Attempts to load a plan stored by [SweepPlan::store].
+    pub fn load(path: &Path) -> Result<SweepPlan, Error> {
+        let contents = fs::read_to_string(path)
+            .context(format!("failed to read plan file {}", path.display()))?;
+        from_str(&contents).context(format!("failed to parse plan file {}", path.display()))
+    }
+
+    /// Logs every entry of the plan without removing anything.
+    pub fn print(&self) {
+        for entry in &self.entries {
+            debug!("Would remove: {:?}", &entry.path);
+        }
+    }
+
+    /// Removes all files and directories in the plan, in order.
+    /// Returns the entries that were successfully removed.
+    pub fn execute(self) -> SweepPlan {
+        let mut removed = SweepPlan::new();
+        for entry in self.entries {
+            let result = if entry.path.is_dir() {
+                remove_dir_all(&entry.path)
+            } else {
+                remove_file(&entry.path)
+            };
+            match result {
+                Ok(_) => {
+                    debug!("Successfully removed: {:?}", &entry.path);
+                    removed.push(entry);
+                }
+                Err(e) => warn!("Failed to remove: {:?} {}", &entry.path, e),
+            };
+        }
+        removed
+    }
+}
+
+impl FromIterator<PlanEntry> for SweepPlan {
+    fn from_iter<I: IntoIterator<Item = PlanEntry>>(iter: I) -> Self {
+        Self {
+            entries: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl IntoIterator for SweepPlan {
+    type Item = PlanEntry;
+    type IntoIter = std::vec::IntoIter<PlanEntry>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    fn entry(path: impl Into<PathBuf>, size: u64) -> PlanEntry {
+        PlanEntry::new(
+            path.into(),
+            "0123456789abcdef".to_string(),
+            size,
+            Reason::Toolchain,
+        )
+    }
+
+    #[test]
+    fn test_merge_and_retain() {
+        let mut plan: SweepPlan = [entry("a", 1), entry("b", 2)].into_iter().collect();
+        plan.merge([entry("b", 2), entry("c", 4)].into_iter().collect());
+        assert_eq!(plan.entries().len(), 3);
+        assert_eq!(plan.total_size(), 7);
+
+        plan.retain(|entry| entry.path != Path::new("c"));
+        assert_eq!(plan.total_size(), 3);
+    }
+
+    #[test]
+    fn test_scope() {
+        let members: HashSet<String> = ["sample-project".to_string()].into_iter().collect();
+        assert!(Scope::All.includes("serde"));
+        assert!(Scope::Only(members.clone()).includes("sample_project"));
+        assert!(!Scope::Only(members.clone()).includes("serde"));
+        assert!(!Scope::Except(members.clone()).includes("sample-project"));
+        assert!(Scope::Except(members).includes("serde"));
+    }
+
+    #[test]
+    fn test_execute() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("libfoo-0123456789abcdef.rlib");
+        let folder = dir.path().join("foo-0123456789abcdef");
+        fs::write(&file, "foo").unwrap();
+        fs::create_dir(&folder).unwrap();
+        fs::write(folder.join("output"), "foo").unwrap();
+
+        let plan: SweepPlan = [
+            entry(&file, 3),
+            entry(&folder, 3),
+            entry(dir.path().join("missing"), 3),
+        ]
+        .into_iter()
+        .collect();
+        let removed = plan.execute();
+
+        let removed_paths = removed.into_iter().map(|entry| entry.path);
+        assert_eq!(
+            removed_paths.collect::<Vec<_>>(),
+            [file.clone(), folder.clone()]
+        );
+        assert!(!file.exists());
+        assert!(!folder.exists());
+    }
+
+    #[test]
+    fn test_store_and_skip_stale() {
+        let dir = tempfile::tempdir().unwrap();
+        let used = dir.path().join("libfoo-0123456789abcdef.rlib");
+        let unused = dir.path().join("libbar-0123456789abcdef.rlib");
+        fs::write(&used, "foo").unwrap();
+        fs::write(&unused, "bar").unwrap();
+
+        let plan: SweepPlan = [
+            entry(&used, 3),
+            entry(&unused, 3),
+            entry(dir.path().join("missing"), 3),
+        ]
+        .into_iter()
+        .collect();
+        let plan_file = dir.path().join("plan.json");
+        plan.store(&plan_file).unwrap();
+
+        File::options()
+            .write(true)
+            .open(&used)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(3600))
+            .unwrap();
+
+        let mut loaded = SweepPlan::load(&plan_file).unwrap();
+        assert_eq!(loaded, plan);
+        loaded.skip_stale();
+        assert_eq!(loaded.entries().len(), 1);
+        assert_eq!(loaded.entries()[0].path, unused);
+    }
+}
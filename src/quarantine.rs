@@ -0,0 +1,384 @@
+use anyhow::{bail, Context, Error};
+use log::{debug, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use walkdir::WalkDir;
+
+use crate::disk_usage::disk_usage;
+use crate::plan::{PlanEntry, SweepPlan};
+
+/// Name of the journal in every run folder, listing what was moved there, one JSON object per line.
+const JOURNAL_FILE: &str = "journal.jsonl";
+
+/// A holding area for swept artifacts, which can be put back until the quarantine is purged.
+///
+/// Every sweep moves its artifacts into a folder of its own, a run, named after the time it started.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quarantine {
+    path: PathBuf,
+}
+
+impl Quarantine {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Starts a new run, its folder is only created once something is moved into it.
+    pub fn begin_run(&self) -> Result<QuarantineRun, Error> {
+        let now = SystemTime::now();
+        let since_epoch = now.duration_since(UNIX_EPOCH)?;
+        let id = format!(
+            "{}-{:09}",
+            since_epoch.as_secs(),
+            since_epoch.subsec_nanos()
+        );
+        Ok(QuarantineRun {
+            path: self.path.join(&id),
+            id,
+            started: now,
+        })
+    }
+
+    /// All runs in the quarantine, from oldest to newest.
+    pub fn runs(&self) -> Result<Vec<QuarantineRun>, Error> {
+        let mut runs = vec![];
+        if !self.path.is_dir() {
+            return Ok(runs);
+        }
+        for entry in fs::read_dir(&self.path)? {
+            let path = entry?.path();
+            let id = path.file_name().unwrap().to_string_lossy().into_owned();
+            let Some(started) = parse_run_id(&id) else {
+                continue;
+            };
+            if path.join(JOURNAL_FILE).is_file() {
+                runs.push(QuarantineRun { path, id, started });
+            }
+        }
+        runs.sort_by_key(|run| run.started);
+        Ok(runs)
+    }
+
+    /// The run with the given id, or the newest one.
+    pub fn run(&self, id: Option<&str>) -> Result<QuarantineRun, Error> {
+        let runs = self.runs()?;
+        let run = match id {
+            Some(id) => runs.into_iter().find(|run| run.id == id),
+            None => runs.into_iter().last(),
+        };
+        match (run, id) {
+            (Some(run), _) => Ok(run),
+            (None, Some(id)) => bail!("No run {id:?} in quarantine {:?}", self.path),
+            (None, None) => bail!("Quarantine {:?} is empty", self.path),
+        }
+    }
+
+    /// The runs that started at least `older_than` ago, which [Quarantine::purge] removes.
+    pub fn runs_older_than(&self, older_than: Duration) -> Result<Vec<QuarantineRun>, Error> {
+        let now = SystemTime::now();
+        Ok(self
+            .runs()?
+            .into_iter()
+            .filter(|run| {
+                now.duration_since(run.started)
+                    .is_ok_and(|age| age >= older_than)
+            })
+            .collect())
+    }
+
+    /// Removes every run that started at least `older_than` ago for good.
+    /// Returns the amount of bytes freed.
+    pub fn purge(&self, older_than: Duration) -> Result<u64, Error> {
+        let mut freed = 0;
+        for run in self.runs_older_than(older_than)? {
+            let size = run.size();
+            match fs::remove_dir_all(&run.path) {
+                Ok(()) => {
+                    debug!("Successfully purged: {:?}", &run.path);
+                    freed += size;
+                }
+                Err(e) => warn!("Failed to purge: {:?} {}", &run.path, e),
+            }
+        }
+        Ok(freed)
+    }
+}
+
+/// Parses the start time from the name of a run folder, `{seconds}-{nanoseconds}` since the Unix epoch.
+fn parse_run_id(id: &str) -> Option<SystemTime> {
+    let (secs, nanos) = id.split_once('-')?;
+    let since_epoch = Duration::new(secs.parse().ok()?, nanos.parse().ok()?);
+    UNIX_EPOCH.checked_add(since_epoch)
+}
+
+/// The artifacts quarantined by a single sweep.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuarantineRun {
+    path: PathBuf,
+    id: String,
+    started: SystemTime,
+}
+
+/// An artifact moved into the quarantine, as recorded in the journal of its run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuarantinedEntry {
+    /// The planned entry, with the path the artifact was moved from.
+    #[serde(flatten)]
+    pub entry: PlanEntry,
+    /// The name of the artifact inside the run folder.
+    pub stored: String,
+}
+
+impl QuarantineRun {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn started(&self) -> SystemTime {
+        self.started
+    }
+
+    /// The space on disk taken up by the run.
+    pub fn size(&self) -> u64 {
+        disk_usage(&self.path)
+    }
+
+    /// Every artifact that is still in the run, in the order it was moved there.
+    pub fn entries(&self) -> Result<Vec<QuarantinedEntry>, Error> {
+        let mut entries = self.journal()?;
+        // The journal is written before moving, the move might have failed or been interrupted.
+        entries.retain(|entry| self.path.join(&entry.stored).symlink_metadata().is_ok());
+        Ok(entries)
+    }
+
+    /// Every entry recorded in the journal, including those that failed to move.
+    fn journal(&self) -> Result<Vec<QuarantinedEntry>, Error> {
+        let journal = self.path.join(JOURNAL_FILE);
+        if !journal.is_file() {
+            return Ok(vec![]);
+        }
+        let contents =
+            fs::read_to_string(&journal).context(format!("Failed to read {journal:?}"))?;
+        contents
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_str(line).context(format!("Failed to parse {journal:?}")))
+            .collect()
+    }
+
+    /// Moves all files and directories in the plan into the run, in order, like [SweepPlan::execute] removes them.
+    /// Returns the entries that were successfully moved.
+    pub fn quarantine(&self, plan: SweepPlan) -> Result<SweepPlan, Error> {
+        if plan.is_empty() {
+            return Ok(plan);
+        }
+        fs::create_dir_all(&self.path).context(format!(
+            "Failed to create quarantine folder {:?}",
+            self.path
+        ))?;
+        let journal_path = self.path.join(JOURNAL_FILE);
+        let mut journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&journal_path)
+            .context(format!("Failed to open {journal_path:?}"))?;
+        let mut moved = SweepPlan::new();
+        // Names are never reused, not even those of entries that failed to move.
+        for (index, entry) in (self.journal()?.len()..).zip(plan) {
+            let file_name = entry.path.file_name().unwrap_or_default().to_string_lossy();
+            let stored = format!("{index}-{file_name}");
+            let quarantined = QuarantinedEntry { entry, stored };
+            // Recorded first, so that an interrupted sweep can still be restored.
+            writeln!(journal, "{}", serde_json::to_string(&quarantined)?)
+                .context(format!("Failed to write {journal_path:?}"))?;
+            let QuarantinedEntry { entry, stored } = quarantined;
+            match move_path(&entry.path, &self.path.join(stored)) {
+                Ok(()) => {
+                    debug!("Successfully quarantined: {:?}", &entry.path);
+                    moved.push(entry);
+                }
+                Err(e) => warn!("Failed to quarantine: {:?} {:?}", &entry.path, e),
+            }
+        }
+        Ok(moved)
+    }
+
+    /// Moves every artifact of the run back to where it was swept from, unless something else is there by now.
+    /// Returns the entries that were restored, the run is removed once nothing is left in it.
+    pub fn restore(self) -> Result<SweepPlan, Error> {
+        let mut restored = SweepPlan::new();
+        let mut left = vec![];
+        for quarantined in self.entries()? {
+            let original = &quarantined.entry.path;
+            if original.symlink_metadata().is_ok() {
+                warn!("Not restoring {original:?} as it was built again since");
+                left.push(quarantined);
+                continue;
+            }
+            let result = original
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .map_err(Error::from)
+                .and_then(|_| move_path(&self.path.join(&quarantined.stored), original));
+            match result {
+                Ok(()) => {
+                    debug!("Successfully restored: {:?}", original);
+                    restored.push(quarantined.entry);
+                }
+                Err(e) => {
+                    warn!("Failed to restore: {:?} {:?}", original, e);
+                    left.push(quarantined);
+                }
+            }
+        }
+        if left.is_empty() {
+            fs::remove_dir_all(&self.path).context(format!("Failed to remove {:?}", self.path))?;
+        } else {
+            let mut journal = String::new();
+            for quarantined in &left {
+                journal += &serde_json::to_string(quarantined)?;
+                journal.push('\n');
+            }
+            let journal_path = self.path.join(JOURNAL_FILE);
+            fs::write(&journal_path, journal)
+                .context(format!("Failed to write {journal_path:?}"))?;
+        }
+        Ok(restored)
+    }
+
+    /// Removes the run if nothing was moved into it.
+    pub fn remove_if_empty(&self) -> Result<(), Error> {
+        if self.path.exists() && self.entries()?.is_empty() {
+            fs::remove_dir_all(&self.path).context(format!("Failed to remove {:?}", self.path))?;
+        }
+        Ok(())
+    }
+}
+
+/// Moves a file or directory, by renaming it if both paths are on the same filesystem,
+/// or else by copying it and removing the original.
+fn move_path(from: &Path, to: &Path) -> Result<(), Error> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    if let Err(e) = copy_path(from, to) {
+        // Don't leave a partial copy behind.
+        let _ = if to.is_dir() {
+            fs::remove_dir_all(to)
+        } else {
+            fs::remove_file(to)
+        };
+        return Err(e);
+    }
+    if from.is_dir() {
+        fs::remove_dir_all(from)?;
+    } else {
+        fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+fn copy_path(from: &Path, to: &Path) -> Result<(), Error> {
+    for entry in WalkDir::new(from) {
+        let entry = entry?;
+        let target = to.join(entry.path().strip_prefix(from)?);
+        let file_type = entry.file_type();
+        if file_type.is_dir() {
+            fs::create_dir(&target)?;
+        } else if file_type.is_symlink() {
+            copy_symlink(entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_symlink(from: &Path, to: &Path) -> Result<(), Error> {
+    std::os::unix::fs::symlink(fs::read_link(from)?, to)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn copy_symlink(from: &Path, to: &Path) -> Result<(), Error> {
+    fs::copy(from, to)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::Reason;
+
+    #[test]
+    fn test_quarantine_and_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let deps = dir.path().join("target/debug/deps");
+        fs::create_dir_all(&deps).unwrap();
+        let file = deps.join("libfoo-0123456789abcdef.rlib");
+        let folder = dir
+            .path()
+            .join("target/debug/incremental/foo-0123456789abcdef");
+        fs::write(&file, "foo").unwrap();
+        fs::create_dir_all(&folder).unwrap();
+        fs::write(folder.join("output"), "foo").unwrap();
+
+        let entry = |path: &Path| {
+            PlanEntry::new(
+                path.to_owned(),
+                "0123456789abcdef".to_string(),
+                3,
+                Reason::Toolchain,
+            )
+        };
+        let plan: SweepPlan = [entry(&file), entry(&folder), entry(&deps.join("missing"))]
+            .into_iter()
+            .collect();
+
+        let quarantine = Quarantine::new(dir.path().join("quarantine"));
+        let run = quarantine.begin_run().unwrap();
+        let moved = run.quarantine(plan).unwrap();
+        assert_eq!(moved.entries().len(), 2);
+        assert!(!file.exists());
+        assert!(!folder.exists());
+        assert_eq!(run.entries().unwrap().len(), 2);
+        assert_eq!(quarantine.runs().unwrap()[0].id(), run.id());
+
+        // The incremental session was rebuilt in the meantime.
+        fs::remove_dir_all(dir.path().join("target/debug/incremental")).unwrap();
+        fs::create_dir_all(&folder).unwrap();
+        let restored = quarantine.run(None).unwrap().restore().unwrap();
+        assert_eq!(restored.entries().len(), 1);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "foo");
+        assert!(!folder.join("output").exists());
+        assert_eq!(run.entries().unwrap().len(), 1);
+
+        assert_eq!(quarantine.purge(Duration::from_secs(3600)).unwrap(), 0);
+        assert!(run.path().exists());
+        assert!(quarantine.purge(Duration::ZERO).unwrap() > 0);
+        assert!(quarantine.runs().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_run_id() {
+        assert_eq!(
+            parse_run_id("1760600000-000000001"),
+            UNIX_EPOCH.checked_add(Duration::new(1_760_600_000, 1))
+        );
+        assert_eq!(parse_run_id("journal.jsonl"), None);
+    }
+}
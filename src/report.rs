@@ -0,0 +1,225 @@
+use anyhow::Error;
+use serde_derive::Serialize;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::disk_usage::DiskUsage;
+use crate::target::{Profile, TargetDir};
+use crate::toolchain::Toolchains;
+
+/// Where the space of a target directory goes, broken down in several ways.
+///
+/// Every breakdown of the units adds up to the space taken up by all units, while `untracked`
+/// accounts for the rest of the target directory, like incremental compilation sessions or docs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Report {
+    pub path: PathBuf,
+    /// Space in bytes taken up on disk by the target directory, and by its build directory if it is elsewhere.
+    pub size: u64,
+    /// Space in bytes taken up by all units tracked by fingerprints.
+    pub units_size: u64,
+    pub by_profile: Vec<Usage>,
+    /// The target triple profiles were built for, or `host` for those directly in the target directory.
+    pub by_target: Vec<Usage>,
+    pub by_crate: Vec<Usage>,
+    /// The toolchain that built the units, see [Toolchain::label](crate::Toolchain::label),
+    /// or the hash of its version if it isn't known.
+    pub by_toolchain: Vec<Usage>,
+    /// The time since the units were last used.
+    pub by_age: Vec<Usage>,
+    /// Everything that doesn't belong to any unit.
+    pub untracked: Vec<Usage>,
+}
+
+/// The space taken up by a group of units or files.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Usage {
+    pub name: String,
+    /// Space in bytes taken up on disk.
+    pub size: u64,
+    /// The number of units in the group, `0` for untracked files.
+    pub units: usize,
+}
+
+/// The upper bounds of the age buckets, in days, and their names. Older units are in a last bucket.
+const AGE_BUCKETS: [(u64, &str); 4] = [
+    (1, "under 1 day"),
+    (7, "1 to 7 days"),
+    (30, "7 to 30 days"),
+    (90, "30 to 90 days"),
+];
+const OLDEST_AGE_BUCKET: &str = "over 90 days";
+
+fn age_bucket(last_used: Duration) -> &'static str {
+    let days = last_used.as_secs() / (24 * 3600);
+    AGE_BUCKETS
+        .iter()
+        .find(|(max, _)| days < *max)
+        .map_or(OLDEST_AGE_BUCKET, |(_, name)| name)
+}
+
+/// Sums up sizes by group name, keeping the order in which the groups were first seen.
+#[derive(Default)]
+struct Breakdown {
+    usages: Vec<Usage>,
+    index: HashMap<String, usize>,
+}
+
+impl Breakdown {
+    fn add(&mut self, name: &str, size: u64, units: usize) {
+        let index = *self.index.entry(name.to_owned()).or_insert_with(|| {
+            self.usages.push(Usage {
+                name: name.to_owned(),
+                size: 0,
+                units: 0,
+            });
+            self.usages.len() - 1
+        });
+        self.usages[index].size += size;
+        self.usages[index].units += units;
+    }
+
+    /// The groups from largest to smallest.
+    fn by_size(self) -> Vec<Usage> {
+        let mut usages = self.in_order();
+        usages.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.name.cmp(&b.name)));
+        usages
+    }
+
+    /// The groups in the order they were first seen, leaving out empty ones.
+    fn in_order(mut self) -> Vec<Usage> {
+        self.usages
+            .retain(|usage| usage.size > 0 || usage.units > 0);
+        self.usages
+    }
+}
+
+impl Report {
+    /// Measures the target directory without changing anything.
+    ///
+    /// `toolchains` are the known toolchains the units may have been built by.
+    pub fn new(target_dir: &TargetDir, toolchains: &Toolchains) -> Result<Self, Error> {
+        // Shared by the whole report, so files hard linked between units or profiles are counted once.
+        let mut usage = DiskUsage::new();
+        let mut by_profile = Breakdown::default();
+        let mut by_target = Breakdown::default();
+        let mut by_crate = Breakdown::default();
+        let mut by_toolchain = Breakdown::default();
+        let mut by_age = Breakdown::default();
+        for (_, name) in AGE_BUCKETS {
+            by_age.add(name, 0, 0);
+        }
+        by_age.add(OLDEST_AGE_BUCKET, 0, 0);
+
+        let profiles: Vec<Profile> = target_dir.profiles().collect();
+        let mut units_size = 0;
+        for profile in &profiles {
+            let target = target_triple(target_dir.build_dir(), profile.path());
+            for unit in profile.units_counted(&mut usage)? {
+                units_size += unit.size;
+                by_profile.add(&profile.name(), unit.size, 1);
+                by_target.add(target.as_deref().unwrap_or("host"), unit.size, 1);
+                by_crate.add(&unit.name, unit.size, 1);
+                by_toolchain.add(&toolchains.label_of(unit.rustc), unit.size, 1);
+                by_age.add(age_bucket(unit.last_used), unit.size, 1);
+            }
+        }
+
+        // Whatever is left once the units are counted, by what it is.
+        let mut untracked = Breakdown::default();
+        for profile in &profiles {
+            untracked.add(
+                "incremental",
+                usage.path(&profile.path().join("incremental")),
+                0,
+            );
+        }
+        let profile_dirs: Vec<PathBuf> = profiles
+            .iter()
+            .flat_map(|profile| {
+                [
+                    profile.path().to_owned(),
+                    target_dir.artifact_dir(profile.path()),
+                ]
+            })
+            .collect();
+        let mut dirs = vec![target_dir.path()];
+        if target_dir.build_dir() != target_dir.path() {
+            dirs.push(target_dir.build_dir());
+        }
+        for dir in dirs {
+            for other in other_dirs(dir, &profile_dirs)? {
+                let name = other.file_name().unwrap().to_string_lossy().into_owned();
+                untracked.add(&name, usage.path(&other), 0);
+            }
+        }
+        let size = target_dir.disk_usage();
+        let counted: u64 = units_size + untracked.usages.iter().map(|u| u.size).sum::<u64>();
+        untracked.add("other", size.saturating_sub(counted), 0);
+
+        Ok(Report {
+            path: target_dir.path().to_owned(),
+            size,
+            units_size,
+            by_profile: by_profile.by_size(),
+            by_target: by_target.by_size(),
+            by_crate: by_crate.by_size(),
+            by_toolchain: by_toolchain.by_size(),
+            by_age: by_age.in_order(),
+            untracked: untracked.by_size(),
+        })
+    }
+}
+
+/// The target triple a profile was built for, e.g. `x86_64-unknown-linux-gnu` for
+/// `target/x86_64-unknown-linux-gnu/debug`, or `None` for a profile of the host.
+fn target_triple(build_dir: &Path, profile: &Path) -> Option<String> {
+    let relative = profile.strip_prefix(build_dir).ok()?;
+    let mut components = relative.components();
+    components.next_back();
+    let triple = components.as_path();
+    (!triple.as_os_str().is_empty()).then(|| triple.to_string_lossy().into_owned())
+}
+
+/// The directories in a target directory that neither are nor hold a profile, like `doc` or `package`.
+fn other_dirs(dir: &Path, profiles: &[PathBuf]) -> Result<Vec<PathBuf>, Error> {
+    let mut others = vec![];
+    if !dir.is_dir() {
+        return Ok(others);
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() && !profiles.iter().any(|profile| profile.starts_with(&path)) {
+            others.push(path);
+        }
+    }
+    others.sort();
+    Ok(others)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_triple() {
+        let target = Path::new("target");
+        assert_eq!(target_triple(target, &target.join("debug")), None);
+        assert_eq!(
+            target_triple(target, &target.join("wasm32-unknown-unknown/release")),
+            Some("wasm32-unknown-unknown".to_string())
+        );
+    }
+
+    #[test]
+    fn test_age_bucket() {
+        let day = 24 * 3600;
+        assert_eq!(age_bucket(Duration::from_secs(3600)), "under 1 day");
+        assert_eq!(age_bucket(Duration::from_secs(7 * day)), "7 to 30 days");
+        assert_eq!(age_bucket(Duration::from_secs(365 * day)), "over 90 days");
+    }
+}
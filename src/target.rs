@@ -0,0 +1,217 @@
+use anyhow::Error;
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::disk_usage::DiskUsage;
+use crate::fingerprint::{
+    hash_from_path_name, last_used_time, total_disk_space_in_a_profile, Fingerprint,
+};
+use crate::incremental::load_sessions;
+use crate::layout::{load_unit_dirs, lookup_all_profiles};
+use crate::usage_log::UsageLog;
+
+/// A Cargo target directory, e.g. `target/` of a project or a shared `CARGO_TARGET_DIR`.
+///
+/// Cargo can keep its intermediate artifacts in a separate build directory, see `build.build-dir`.
+/// The target directory then only holds the final artifacts uplifted from it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetDir {
+    path: PathBuf,
+    build_dir: Option<PathBuf>,
+}
+
+impl TargetDir {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            build_dir: None,
+        }
+    }
+
+    /// Sets the build directory holding the intermediate artifacts, if it differs from the target directory.
+    pub fn with_build_dir(mut self, build_dir: impl Into<PathBuf>) -> Self {
+        let build_dir = build_dir.into();
+        self.build_dir = (build_dir != self.path).then_some(build_dir);
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The directory holding the intermediate artifacts, which is the target directory unless set otherwise.
+    pub fn build_dir(&self) -> &Path {
+        self.build_dir.as_deref().unwrap_or(&self.path)
+    }
+
+    /// All profiles in the build directory, that is every directory containing units in either of Cargo's layouts.
+    /// This includes profiles nested below a target triple, like `target/x86_64-unknown-linux-gnu/debug`.
+    pub fn profiles(&self) -> impl Iterator<Item = Profile> + '_ {
+        lookup_all_profiles(self.build_dir())
+            .into_iter()
+            .map(|profile| Profile {
+                artifact_dir: self.artifact_dir(&profile),
+                path: profile,
+            })
+    }
+
+    /// The directory Cargo uplifts the final artifacts of a profile in the build directory to.
+    pub(crate) fn artifact_dir(&self, profile: &Path) -> PathBuf {
+        match profile.strip_prefix(self.build_dir()) {
+            Ok(relative) => self.path.join(relative),
+            Err(_) => profile.to_owned(),
+        }
+    }
+
+    /// The space taken up on disk by the target directory, and by the build directory if it is elsewhere.
+    pub fn disk_usage(&self) -> u64 {
+        let mut usage = DiskUsage::new();
+        match &self.build_dir {
+            Some(build_dir) if build_dir.starts_with(&self.path) => usage.path(&self.path),
+            Some(build_dir) if self.path.starts_with(build_dir) => usage.path(build_dir),
+            Some(build_dir) => usage.path(&self.path) + usage.path(build_dir),
+            None => usage.path(&self.path),
+        }
+    }
+
+    /// The hashes of every unit of the named crates, in any profile of this target directory.
+    /// This includes the hashes of their incremental compilation sessions.
+    pub fn hashes_of(&self, names: &[String]) -> Result<HashSet<String>, Error> {
+        let mut hashes = HashSet::new();
+        for profile in self.profiles() {
+            if profile.fingerprint_dir().is_dir() {
+                for entry in fs::read_dir(profile.fingerprint_dir())? {
+                    let file_name = entry?.file_name();
+                    if let Some((name, hash)) = split_unit_name(&file_name.to_string_lossy()) {
+                        if names.iter().any(|n| n == name) {
+                            hashes.insert(hash.to_owned());
+                        }
+                    }
+                }
+            }
+            for unit in load_unit_dirs(profile.path())? {
+                if names.contains(&unit.package) {
+                    hashes.insert(unit.hash);
+                }
+            }
+            for session in load_sessions(profile.path())? {
+                if names.iter().any(|n| n.replace('-', "_") == session.name) {
+                    hashes.insert(session.hash);
+                }
+            }
+        }
+        Ok(hashes)
+    }
+}
+
+/// Splits the name of a fingerprint folder like `serde-0123456789abcdef` into the unit name and hash.
+fn split_unit_name(file_name: &str) -> Option<(&str, &str)> {
+    let hash = hash_from_path_name(file_name)?;
+    let name = file_name
+        .split('.')
+        .next()?
+        .strip_suffix(hash)?
+        .strip_suffix('-')?;
+    Some((name, hash))
+}
+
+/// A profile directory inside of a target or build directory, e.g. `target/debug`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    path: PathBuf,
+    /// Where the final artifacts of the profile are uplifted to, which differs from `path` with a separate build directory.
+    artifact_dir: PathBuf,
+}
+
+impl Profile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        Self {
+            artifact_dir: path.clone(),
+            path,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The name of the profile, e.g. `debug` or `release`.
+    pub fn name(&self) -> String {
+        self.path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    /// The fingerprints of the classic layout, the per-package layout keeps them in every unit directory.
+    pub fn fingerprint_dir(&self) -> PathBuf {
+        self.path.join(".fingerprint")
+    }
+
+    /// Loads every unit tracked by the fingerprints of this profile, sorted from most to least recently used.
+    pub fn units(&self) -> Result<Vec<Unit>, Error> {
+        self.units_counted(&mut DiskUsage::new())
+    }
+
+    /// Like [Profile::units], but leaves out of the sizes any file `usage` already counted.
+    pub(crate) fn units_counted(&self, usage: &mut DiskUsage) -> Result<Vec<Unit>, Error> {
+        let sizes = total_disk_space_in_a_profile(&self.path, &self.artifact_dir, usage)?;
+        let mut fingerprints = vec![];
+        if self.fingerprint_dir().is_dir() {
+            for entry in fs::read_dir(self.fingerprint_dir())? {
+                let path = entry?.path();
+                if !path.is_dir() {
+                    continue;
+                }
+                let file_name = path.file_name().unwrap().to_string_lossy();
+                if let Some((name, hash)) = split_unit_name(&file_name) {
+                    fingerprints.push((name.to_owned(), hash.to_owned(), path.clone()));
+                }
+            }
+        }
+        for unit in load_unit_dirs(&self.path)? {
+            fingerprints.push((
+                unit.package.clone(),
+                unit.hash.clone(),
+                unit.fingerprint_dir(),
+            ));
+        }
+        let log = UsageLog::load(&self.path)?;
+        let mut units = vec![];
+        for (name, hash, path) in fingerprints {
+            if !path.is_dir() {
+                continue;
+            }
+            units.push(Unit {
+                size: sizes.get(&hash).copied().unwrap_or(0),
+                rustc: Fingerprint::load(&path).ok().map(|f| f.rustc),
+                last_used: log.last_used(&hash, last_used_time(&path)?),
+                name,
+                hash,
+            });
+        }
+        units.sort_unstable_by(|a, b| a.last_used.cmp(&b.last_used).then(a.hash.cmp(&b.hash)));
+        Ok(units)
+    }
+}
+
+/// A single compilation unit tracked by Cargo's fingerprints, e.g. one build of `serde`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unit {
+    /// The crate or build script name, e.g. `serde` or `build-script-build`.
+    pub name: String,
+    /// The 16 hex digit hash Cargo appends to every file belonging to this unit, or names its directory after.
+    pub hash: String,
+    /// Hash of the `rustc -vV` output of the compiler that built this unit, if it could be read.
+    pub rustc: Option<u64>,
+    /// Space in bytes taken up on disk by all files belonging to this unit.
+    pub size: u64,
+    /// Time elapsed since any file of this unit's fingerprint was last accessed, or since rustc
+    /// last used the unit if that is more recent, see [crate::rustc_wrapper].
+    pub last_used: Duration,
+}
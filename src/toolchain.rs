@@ -0,0 +1,264 @@
+use anyhow::{Context, Error};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::fingerprint::{hash_u64, hash_u64_old, rustc_version, rustup_toolchain_list};
+use crate::target::TargetDir;
+
+/// Name of the file Cargo caches the output of rustc in, in every target directory.
+const RUSTC_INFO_FILE: &str = ".rustc_info.json";
+
+/// A compiler that built units, as told by the output of `rustc -vV`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Toolchain {
+    /// The output of `rustc -vV`, which Cargo hashes into [Unit::rustc](crate::Unit::rustc).
+    pub version: String,
+    /// The name of the rustup toolchain, if it is installed.
+    pub name: Option<String>,
+}
+
+impl Toolchain {
+    fn field(&self, key: &str) -> Option<&str> {
+        self.version
+            .lines()
+            .find_map(|line| line.strip_prefix(key)?.strip_prefix(": "))
+    }
+
+    /// The version of the compiler, e.g. `1.78.0` or `1.79.0-nightly`.
+    pub fn release(&self) -> Option<&str> {
+        self.field("release")
+    }
+
+    /// A name for the toolchain: the rustup name if it is installed, otherwise its release,
+    /// with the date for nightlies since these share a release for weeks, e.g. `1.79.0-nightly (2024-03-01)`.
+    pub fn label(&self) -> String {
+        if let Some(name) = &self.name {
+            return name.clone();
+        }
+        match (self.release(), self.field("commit-date")) {
+            (Some(release), Some(date)) if release.ends_with("-nightly") => {
+                format!("{release} ({date})")
+            }
+            (Some(release), _) => release.to_owned(),
+            // Not the output of `rustc -vV` as we know it, so show all of it.
+            (None, _) => self.version.lines().next().unwrap_or_default().to_owned(),
+        }
+    }
+}
+
+/// The toolchains known to have built units, by the hashes of their version fingerprints refer to.
+///
+/// Besides the installed toolchains, these are the ones Cargo recorded in `.rustc_info.json`,
+/// and the ones seen by earlier runs if they are stored, so that units built by a toolchain
+/// that was uninstalled since can still be told apart.
+#[derive(Debug, Default)]
+pub struct Toolchains {
+    toolchains: Vec<Toolchain>,
+    by_hash: HashMap<u64, usize>,
+}
+
+/// The file toolchains are stored in, only the versions are kept since names change with updates.
+#[derive(Serialize, Deserialize)]
+struct StoredToolchains {
+    versions: Vec<String>,
+}
+
+/// The output of rustc as cached by Cargo in `.rustc_info.json`.
+#[derive(Deserialize)]
+struct RustcInfo {
+    outputs: HashMap<String, RustcOutput>,
+}
+
+#[derive(Deserialize)]
+struct RustcOutput {
+    success: bool,
+    stdout: String,
+}
+
+impl Toolchains {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The file toolchains are stored in by default, in the Cargo home directory.
+    pub fn default_path() -> Option<PathBuf> {
+        home::cargo_home()
+            .ok()
+            .map(|home| home.join("cargo-sweep").join("toolchains.json"))
+    }
+
+    /// Loads the toolchains stored by [Toolchains::store], none if the file doesn't exist.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let mut toolchains = Self::new();
+        if !path.exists() {
+            return Ok(toolchains);
+        }
+        let contents = fs::read_to_string(path).context(format!("Failed to read {path:?}"))?;
+        let stored: StoredToolchains =
+            serde_json::from_str(&contents).context(format!("Failed to parse {path:?}"))?;
+        for version in stored.versions {
+            toolchains.add(version, None);
+        }
+        Ok(toolchains)
+    }
+
+    /// Stores the versions of all toolchains, so that they are known after they are uninstalled.
+    pub fn store(&self, path: &Path) -> Result<(), Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context(format!("Failed to create {parent:?}"))?;
+        }
+        let stored = StoredToolchains {
+            versions: self
+                .iter()
+                .map(|toolchain| toolchain.version.clone())
+                .collect(),
+        };
+        let contents = serde_json::to_string_pretty(&stored)?;
+        fs::write(path, contents).context(format!("Failed to write {path:?}"))
+    }
+
+    /// Adds a toolchain by its `rustc -vV` output, naming it if it wasn't named yet.
+    pub fn add(&mut self, version: String, name: Option<String>) {
+        let hash = hash_u64(&version);
+        if let Some(&index) = self.by_hash.get(&hash) {
+            let toolchain = &mut self.toolchains[index];
+            if toolchain.name.is_none() {
+                toolchain.name = name;
+            }
+            return;
+        }
+        let index = self.toolchains.len();
+        self.by_hash.insert(hash, index);
+        self.by_hash.insert(hash_u64_old(&version), index);
+        self.toolchains.push(Toolchain { version, name });
+    }
+
+    /// Adds every toolchain installed by rustup, or the `rustc` in the path without rustup.
+    /// Toolchains that fail to report their version are left out.
+    pub fn add_installed(&mut self) {
+        let toolchains = match rustup_toolchain_list() {
+            Some(list) => list.into_iter().map(Some).collect(),
+            None => vec![None],
+        };
+        for toolchain in toolchains {
+            let Ok(out) = rustc_version(toolchain.as_deref()) else {
+                continue;
+            };
+            if !out.status.success() {
+                continue;
+            }
+            let version = String::from_utf8_lossy(&out.stdout).into_owned();
+            self.add(
+                version,
+                Some(toolchain.unwrap_or_else(|| "rustc".to_string())),
+            );
+        }
+    }
+
+    /// Adds the toolchains Cargo recorded in `.rustc_info.json` in the target directory, or in its build directory.
+    pub fn add_rustc_info(&mut self, target_dir: &TargetDir) -> Result<(), Error> {
+        for dir in [target_dir.path(), target_dir.build_dir()] {
+            let path = dir.join(RUSTC_INFO_FILE);
+            if !path.is_file() {
+                continue;
+            }
+            let contents = fs::read_to_string(&path).context(format!("Failed to read {path:?}"))?;
+            let info: RustcInfo =
+                serde_json::from_str(&contents).context(format!("Failed to parse {path:?}"))?;
+            // The outputs are keyed by a hash of the arguments, so look for the one of `rustc -vV`.
+            for output in info.outputs.into_values() {
+                if output.success
+                    && output.stdout.starts_with("rustc ")
+                    && output.stdout.contains("\nrelease: ")
+                {
+                    self.add(output.stdout, None);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The toolchain whose version hashes to [Unit::rustc](crate::Unit::rustc).
+    pub fn get(&self, rustc: u64) -> Option<&Toolchain> {
+        self.by_hash
+            .get(&rustc)
+            .map(|&index| &self.toolchains[index])
+    }
+
+    /// A name for the toolchain that built a unit with the given [Unit::rustc](crate::Unit::rustc),
+    /// its [Toolchain::label] or the hash of its version if it isn't known.
+    pub fn label_of(&self, rustc: Option<u64>) -> String {
+        match rustc {
+            // Build script outputs aren't built by any compiler.
+            Some(0) => "none".to_owned(),
+            Some(rustc) => self
+                .get(rustc)
+                .map_or_else(|| format!("{rustc:016x}"), Toolchain::label),
+            None => "unknown".to_owned(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Toolchain> {
+        self.toolchains.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NIGHTLY: &str = "rustc 1.79.0-nightly (0bf471f33 2024-03-01)\nbinary: rustc\ncommit-hash: 0bf471f339837af930ec90ef5e1e9cb232e99f29\ncommit-date: 2024-03-01\nhost: x86_64-unknown-linux-gnu\nrelease: 1.79.0-nightly\nLLVM version: 18.1.2\n";
+
+    #[test]
+    fn test_label() {
+        let mut toolchain = Toolchain {
+            version: NIGHTLY.to_string(),
+            name: None,
+        };
+        assert_eq!(toolchain.release(), Some("1.79.0-nightly"));
+        assert_eq!(toolchain.label(), "1.79.0-nightly (2024-03-01)");
+        toolchain.name = Some("nightly-2024-03-02".to_string());
+        assert_eq!(toolchain.label(), "nightly-2024-03-02");
+    }
+
+    #[test]
+    fn test_rustc_info_and_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let info = serde_json::json!({
+            "rustc_fingerprint": 1,
+            "outputs": {
+                "1": { "success": true, "status": "", "code": 0, "stdout": "___\nlib___.rlib\n", "stderr": "" },
+                "2": { "success": true, "status": "", "code": 0, "stdout": NIGHTLY, "stderr": "" },
+            },
+            "successes": {},
+        });
+        fs::write(dir.path().join(RUSTC_INFO_FILE), info.to_string()).unwrap();
+
+        let mut toolchains = Toolchains::new();
+        toolchains
+            .add_rustc_info(&TargetDir::new(dir.path()))
+            .unwrap();
+        assert_eq!(toolchains.iter().count(), 1);
+        let hash = hash_u64(&NIGHTLY.to_string());
+        assert_eq!(toolchains.get(hash).unwrap().version, NIGHTLY);
+        assert!(toolchains.get(hash_u64_old(&NIGHTLY.to_string())).is_some());
+
+        let path = dir.path().join("sweep").join("toolchains.json");
+        toolchains.store(&path).unwrap();
+        let loaded = Toolchains::load(&path).unwrap();
+        assert_eq!(loaded.get(hash).unwrap().version, NIGHTLY);
+        // Stored toolchains are labelled by their version, as they may not be installed anymore.
+        assert_eq!(loaded.label_of(Some(hash)), "1.79.0-nightly (2024-03-01)");
+        assert_eq!(loaded.label_of(Some(1)), "0000000000000001");
+        assert_eq!(loaded.label_of(Some(0)), "none");
+        assert_eq!(loaded.label_of(None), "unknown");
+        assert!(Toolchains::load(&dir.path().join("missing.json"))
+            .unwrap()
+            .get(hash)
+            .is_none());
+    }
+}
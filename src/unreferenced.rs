@@ -0,0 +1,141 @@
+use anyhow::Error;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::fingerprint::{all_fingerprints, hashed_outputs, package_name, unit_dir_outputs};
+use crate::layout::load_unit_dirs;
+
+/// The packages workspaces can depend on, by name, with the directory of every version of them,
+/// as resolved by `cargo metadata`. See [Criterion::Unreferenced](crate::Criterion::Unreferenced).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DependencyGraph {
+    packages: HashMap<String, HashSet<PathBuf>>,
+}
+
+impl DependencyGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a package by its name and the directory holding its `Cargo.toml`.
+    pub fn add_package(&mut self, name: impl Into<String>, dir: impl Into<PathBuf>) {
+        self.packages
+            .entry(name.into())
+            .or_default()
+            .insert(dir.into());
+    }
+
+    /// Whether a unit of the named package, built from the given source files, may still be used.
+    fn references(&self, name: &str, sources: Option<&[PathBuf]>) -> bool {
+        let Some(dirs) = self.packages.get(name) else {
+            return false;
+        };
+        match sources {
+            // Packages outside of the workspace are built from absolute paths, which tell their version apart.
+            Some(sources) if !sources.is_empty() && sources.iter().all(|s| s.is_absolute()) => {
+                sources
+                    .iter()
+                    .any(|source| dirs.iter().any(|dir| source.starts_with(dir)))
+            }
+            // Workspace members are built from paths relative to the workspace, and there is
+            // no telling which version the output of a build script belongs to.
+            _ => true,
+        }
+    }
+}
+
+/// Loads the hashes of the units in a profile that belong to a package, or a version of it,
+/// the dependency graph doesn't have.
+pub(crate) fn load_unreferenced(
+    profile: &Path,
+    graph: &DependencyGraph,
+) -> Result<HashSet<String>, Error> {
+    let sources = load_sources(profile)?;
+    let mut unreferenced = HashSet::new();
+    for (path, hash) in all_fingerprints(profile)? {
+        let name = package_name(&path, &hash);
+        if !graph.references(&name, sources.get(&hash).map(Vec::as_slice)) {
+            unreferenced.insert(hash);
+        }
+    }
+    Ok(unreferenced)
+}
+
+/// The source files every unit in a profile was built from, by its hash,
+/// as listed in the dep-info files rustc writes next to its outputs.
+fn load_sources(profile: &Path) -> Result<HashMap<String, Vec<PathBuf>>, Error> {
+    let mut dep_infos = vec![];
+    for dir in ["deps", "examples"] {
+        dep_infos.extend(hashed_outputs(&profile.join(dir))?);
+    }
+    // Build scripts are built into a directory of their own, like `build/foo-{hash}/build_script_build-{hash}.d`.
+    for build_dir in hashed_outputs(&profile.join("build"))? {
+        if build_dir.path.is_dir() {
+            dep_infos.extend(hashed_outputs(&build_dir.path)?);
+        }
+    }
+    dep_infos.extend(unit_dir_outputs(&load_unit_dirs(profile)?)?);
+
+    let mut sources: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    for dep_info in dep_infos {
+        if dep_info.path.extension().is_some_and(|ext| ext == "d") {
+            if let Ok(contents) = fs::read_to_string(&dep_info.path) {
+                sources
+                    .entry(dep_info.hash)
+                    .or_default()
+                    .extend(parse_dep_info(&contents));
+            }
+        }
+    }
+    Ok(sources)
+}
+
+/// Parses the source files out of a Makefile style dep-info file written by rustc.
+/// Every source file is listed as a target of its own, like `src/lib.rs:`, after the rule for the output.
+fn parse_dep_info(contents: &str) -> Vec<PathBuf> {
+    contents
+        .lines()
+        .skip(1)
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.strip_suffix(':'))
+        .map(|source| PathBuf::from(source.replace("\\ ", " ")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dep_info() {
+        let contents = "/t/debug/deps/foo-0123456789abcdef.d: /r/foo-1.0.0/src/lib.rs /r/foo-1.0.0/src/a\\ b.rs\n\n\
+            /r/foo-1.0.0/src/lib.rs:\n/r/foo-1.0.0/src/a\\ b.rs:\n\n# env-dep:FOO=bar\n";
+        assert_eq!(
+            parse_dep_info(contents),
+            [
+                PathBuf::from("/r/foo-1.0.0/src/lib.rs"),
+                PathBuf::from("/r/foo-1.0.0/src/a b.rs")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_references() {
+        // Any absolute path will do.
+        let registry = std::env::temp_dir();
+        let mut graph = DependencyGraph::new();
+        graph.add_package("foo", registry.join("foo-1.1.0"));
+        let sources = |path: &str| vec![registry.join(path)];
+
+        assert!(graph.references("foo", Some(&sources("foo-1.1.0/src/lib.rs"))));
+        assert!(!graph.references("foo", Some(&sources("foo-1.0.0/src/lib.rs"))));
+        assert!(!graph.references("bar", Some(&sources("bar-1.0.0/src/lib.rs"))));
+        // Can't tell the version apart, so it is kept as long as the package is there.
+        assert!(graph.references("foo", Some(&[PathBuf::from("src/lib.rs")])));
+        assert!(graph.references("foo", None));
+        assert!(!graph.references("bar", None));
+    }
+}
@@ -0,0 +1,327 @@
+use anyhow::{Context, Error};
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::fingerprint::{all_fingerprints, hash_from_path_name};
+use crate::layout::is_hash;
+use crate::lock::CARGO_LOCK_FILE;
+
+/// Name of the file the rustc wrapper records the units it sees in, in every profile directory,
+/// e.g. `target/debug/.cargo-sweep-usage`.
+pub const USAGE_LOG_FILE: &str = ".cargo-sweep-usage";
+
+/// When rustc last built or linked against every unit of a profile, as recorded by [rustc_wrapper].
+///
+/// Every line of the log is the time in seconds since the Unix epoch, the crate name and the hash
+/// of the unit, separated by tabs. Lines that can't be parsed, e.g. if a write was cut short, are skipped.
+#[derive(Debug, Default)]
+pub(crate) struct UsageLog {
+    last_used: HashMap<String, SystemTime>,
+}
+
+impl UsageLog {
+    /// Loads the log of a profile, which is empty if the wrapper never ran in it.
+    pub(crate) fn load(profile: &Path) -> Result<Self, Error> {
+        let path = profile.join(USAGE_LOG_FILE);
+        let mut log = Self::default();
+        if !path.is_file() {
+            return Ok(log);
+        }
+        let contents = fs::read_to_string(&path).context(format!("Failed to read {path:?}"))?;
+        for (time, _, hash) in contents.lines().filter_map(parse_line) {
+            let last_used = log.last_used.entry(hash.to_owned()).or_insert(time);
+            *last_used = (*last_used).max(time);
+        }
+        Ok(log)
+    }
+
+    /// The time elapsed since the unit was last used, the more recent of what the log and
+    /// the access times of its fingerprint say.
+    ///
+    /// Cargo doesn't run rustc for units that are fresh, so only the access times tell that these
+    /// were used. The log makes up for access times that aren't updated, like with `noatime`.
+    pub(crate) fn last_used(&self, hash: &str, accessed: Duration) -> Duration {
+        let logged = self
+            .last_used
+            .get(hash)
+            .map(|time| time.elapsed().unwrap_or(Duration::ZERO));
+        logged.map_or(accessed, |logged| logged.min(accessed))
+    }
+}
+
+fn parse_line(line: &str) -> Option<(SystemTime, &str, &str)> {
+    let mut fields = line.split('\t');
+    let time: f64 = fields.next()?.parse().ok()?;
+    let name = fields.next()?;
+    let hash = fields.next()?;
+    let time = UNIX_EPOCH.checked_add(Duration::try_from_secs_f64(time).ok()?)?;
+    Some((time, name, hash))
+}
+
+/// Rewrites the log of a profile with only the latest line of every unit that still exists.
+///
+/// The log grows with every build otherwise. Cargo must not be building in the profile,
+/// see [crate::TargetLock].
+pub fn compact_usage_log(profile: &Path) -> Result<(), Error> {
+    let path = profile.join(USAGE_LOG_FILE);
+    if !path.is_file() {
+        return Ok(());
+    }
+    let existing: HashSet<String> = all_fingerprints(profile)?
+        .into_iter()
+        .map(|(_, hash)| hash)
+        .collect();
+    let contents = fs::read_to_string(&path).context(format!("Failed to read {path:?}"))?;
+    let mut latest: HashMap<&str, (SystemTime, &str)> = HashMap::new();
+    for (time, name, hash) in contents.lines().filter_map(parse_line) {
+        if !existing.contains(hash) {
+            continue;
+        }
+        let entry = latest.entry(hash).or_insert((time, name));
+        if time > entry.0 {
+            *entry = (time, name);
+        }
+    }
+    let mut lines: Vec<_> = latest
+        .into_iter()
+        .map(|(hash, (time, name))| (time, name, hash))
+        .collect();
+    lines.sort_unstable();
+    let contents: String = lines
+        .into_iter()
+        .map(|(time, name, hash)| format_line(time, name, hash))
+        .collect();
+    fs::write(&path, contents).context(format!("Failed to write {path:?}"))
+}
+
+fn format_line(time: SystemTime, name: &str, hash: &str) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!(
+        "{}.{:09}\t{name}\t{hash}\n",
+        since_epoch.as_secs(),
+        since_epoch.subsec_nanos()
+    )
+}
+
+/// Runs rustc as a `RUSTC_WRAPPER`, and records the unit it built and the units it linked against
+/// in the [USAGE_LOG_FILE] of their profiles once it succeeds.
+///
+/// `args` are those Cargo passes to the wrapper: the path to rustc followed by its arguments.
+/// Failing to record is only reported, so that it never fails the build.
+pub fn rustc_wrapper(args: Vec<OsString>) -> Result<ExitStatus, Error> {
+    let (rustc, rustc_args) = args
+        .split_first()
+        .context("Expected the path to rustc as the first argument")?;
+    let status = Command::new(rustc)
+        .args(rustc_args)
+        .status()
+        .context(format!("Failed to run {rustc:?}"))?;
+    if status.success() {
+        let rustc_args: Vec<_> = rustc_args.iter().map(|arg| arg.to_string_lossy()).collect();
+        let rustc_args: Vec<&str> = rustc_args.iter().map(AsRef::as_ref).collect();
+        if let Err(e) = record(&used_units(&rustc_args), SystemTime::now()) {
+            // Cargo passes on what rustc prints to stderr if it isn't JSON.
+            eprintln!("warning: cargo-sweep failed to record the units used: {e:#}");
+        }
+    }
+    Ok(status)
+}
+
+/// A unit rustc used, with the crate name, its hash and the profile it is in.
+#[derive(Debug, PartialEq, Eq)]
+struct UsedUnit {
+    name: String,
+    hash: String,
+    profile: PathBuf,
+}
+
+/// The unit rustc builds with the given arguments, and those it links against with `--extern`.
+/// Nothing is used by the invocations Cargo makes to learn about rustc, like `rustc -vV`.
+fn used_units(args: &[&str]) -> Vec<UsedUnit> {
+    let mut crate_name = None;
+    let mut out_dir = None;
+    let mut extra_filename = None;
+    let mut metadata = None;
+    let mut externs = vec![];
+    let mut args = args.iter().copied();
+    while let Some(arg) = args.next() {
+        // Options are passed both as `--opt value` and `--opt=value`, codegen options also as `-Cname=value`.
+        let (option, value) = match arg.split_once('=') {
+            Some((option, value)) if option.starts_with("--") => (option, Some(value)),
+            _ => match arg.strip_prefix("-C").filter(|value| !value.is_empty()) {
+                Some(value) => ("-C", Some(value)),
+                None => (arg, None),
+            },
+        };
+        let mut value = || value.or_else(|| args.next());
+        match option {
+            "--crate-name" => crate_name = value(),
+            "--out-dir" => out_dir = value(),
+            "--extern" => externs.extend(value()),
+            "-C" | "--codegen" => match value().and_then(|value| value.split_once('=')) {
+                Some(("extra-filename", value)) => extra_filename = Some(value),
+                Some(("metadata", value)) => metadata = Some(value),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    let mut used = vec![];
+    // Cargo learns about rustc with `___` as the crate name.
+    if let (Some(name), Some(out_dir)) = (crate_name.filter(|name| *name != "___"), out_dir) {
+        let out_dir = Path::new(out_dir);
+        // The per-package layout names the unit directory after the hash: `build/{pkg}/{hash}/out`.
+        let hash = extra_filename
+            .map(|extra| extra.trim_start_matches('-'))
+            .filter(|hash| is_hash(hash))
+            .or_else(|| {
+                out_dir
+                    .parent()
+                    .and_then(Path::file_name)
+                    .and_then(|name| name.to_str())
+                    .filter(|name| is_hash(name))
+            })
+            .or(metadata.filter(|hash| is_hash(hash)));
+        if let (Some(hash), Some(profile)) = (hash, profile_of(out_dir)) {
+            used.push(UsedUnit {
+                name: name.to_owned(),
+                hash: hash.to_owned(),
+                profile,
+            });
+        }
+    }
+    for extern_ in externs {
+        // `--extern name` without a path refers to the sysroot, `--extern noprelude:name=path` has options.
+        let Some((name, path)) = extern_.split_once('=') else {
+            continue;
+        };
+        let name = name.rsplit(':').next().unwrap_or(name);
+        let path = Path::new(path);
+        let hash = path
+            .file_name()
+            .and_then(|name| hash_from_path_name(name.to_str()?))
+            .or_else(|| {
+                let out = path.parent().filter(|dir| dir.ends_with("out"))?;
+                out.parent()?
+                    .file_name()?
+                    .to_str()
+                    .filter(|name| is_hash(name))
+            });
+        if let (Some(hash), Some(profile)) = (hash, profile_of(path)) {
+            used.push(UsedUnit {
+                name: name.to_owned(),
+                hash: hash.to_owned(),
+                profile,
+            });
+        }
+    }
+    used
+}
+
+/// The profile directory the output of a unit is in, the closest directory above it
+/// with fingerprints or Cargo's lock file.
+fn profile_of(path: &Path) -> Option<PathBuf> {
+    path.ancestors()
+        .skip(1)
+        .find(|dir| dir.join(".fingerprint").is_dir() || dir.join(CARGO_LOCK_FILE).is_file())
+        .map(Path::to_owned)
+}
+
+/// Appends the used units to the logs of their profiles, with a single write per log so that
+/// lines of rustc processes running in parallel don't end up mixed.
+fn record(used: &[UsedUnit], time: SystemTime) -> Result<(), Error> {
+    let mut logs: HashMap<&Path, String> = HashMap::new();
+    for unit in used {
+        logs.entry(&unit.profile)
+            .or_default()
+            .push_str(&format_line(time, &unit.name, &unit.hash));
+    }
+    for (profile, lines) in logs {
+        let path = profile.join(USAGE_LOG_FILE);
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(lines.as_bytes()))
+            .context(format!("Failed to write {path:?}"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_used_units() {
+        let dir = tempfile::tempdir().unwrap();
+        let profile = dir.path().join("debug");
+        fs::create_dir_all(profile.join(".fingerprint")).unwrap();
+        let deps = profile.join("deps");
+        let out_dir = deps.to_string_lossy();
+        let serde = deps.join("libserde-0123456789abcdef.rlib");
+        let extern_ = format!("serde={}", serde.display());
+        let args = [
+            "--crate-name",
+            "app",
+            "--edition=2021",
+            "-C",
+            "metadata=fedcba9876543210",
+            "-Cextra-filename=-fedcba9876543210",
+            "--out-dir",
+            &out_dir,
+            "--extern",
+            &extern_,
+            "--extern",
+            "proc_macro",
+        ];
+        let used = used_units(&args);
+        assert_eq!(
+            used,
+            [
+                UsedUnit {
+                    name: "app".to_string(),
+                    hash: "fedcba9876543210".to_string(),
+                    profile: profile.clone(),
+                },
+                UsedUnit {
+                    name: "serde".to_string(),
+                    hash: "0123456789abcdef".to_string(),
+                    profile: profile.clone(),
+                },
+            ]
+        );
+        assert!(used_units(&["-vV"]).is_empty());
+        assert!(used_units(&["-", "--crate-name", "___", "--print=file-names"]).is_empty());
+
+        let hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        record(&used, hour_ago).unwrap();
+        record(&used[..1], SystemTime::now()).unwrap();
+        let log = UsageLog::load(&profile).unwrap();
+        let long_ago = Duration::from_secs(24 * 3600);
+        assert!(log.last_used("fedcba9876543210", long_ago) < Duration::from_secs(60));
+        let serde_used = log.last_used("0123456789abcdef", long_ago);
+        assert!(serde_used >= Duration::from_secs(3600) && serde_used < long_ago);
+        // Access times still count when they are more recent.
+        assert_eq!(
+            log.last_used("0123456789abcdef", Duration::ZERO),
+            Duration::ZERO
+        );
+        assert_eq!(log.last_used("0000000000000000", long_ago), long_ago);
+
+        // Only the app still exists.
+        fs::create_dir(profile.join(".fingerprint/app-fedcba9876543210")).unwrap();
+        compact_usage_log(&profile).unwrap();
+        let contents = fs::read_to_string(profile.join(USAGE_LOG_FILE)).unwrap();
+        assert_eq!(contents.lines().count(), 1);
+        assert!(contents.ends_with("\tapp\tfedcba9876543210\n"));
+    }
+}
@@ -9,7 +9,7 @@ use std::{
 
 use anyhow::{Context, Result};
 use assert_cmd::{assert::Assert, cargo::cargo_bin, Command};
-use fs_extra::dir::{get_size, CopyOptions};
+use fs_extra::dir::CopyOptions;
 use predicates::{prelude::PredicateBooleanExt, str::contains};
 #[allow(unused_imports)]
 use pretty_assertions::{assert_eq, assert_ne};
@@ -43,6 +43,34 @@ fn project_dir(project: &str) -> PathBuf {
     test_dir().join(project)
 }
 
+/// The space taken up on disk by the files in `path`, counting hard links once like `du` does.
+/// This is measured independently of `cargo_sweep::disk_usage`, so that it can check its results.
+#[cfg(unix)]
+fn get_size(path: impl AsRef<Path>) -> Result<u64> {
+    use std::{collections::HashSet, os::unix::fs::MetadataExt};
+    let mut seen = HashSet::new();
+    let mut size = 0;
+    let mut dirs = vec![path.as_ref().to_owned()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+            } else if metadata.is_file() && seen.insert((metadata.dev(), metadata.ino())) {
+                size += metadata.blocks() * 512;
+            }
+        }
+    }
+    Ok(size)
+}
+
+/// The size of the files in `path`, `cargo-sweep` can't tell hard links apart on other platforms either.
+#[cfg(not(unix))]
+fn get_size(path: impl AsRef<Path>) -> Result<u64> {
+    Ok(fs_extra::dir::get_size(path)?)
+}
+
 fn cargo(cmd_current_dir: impl AsRef<Path>) -> Command {
     let mut cmd = Command::new(env!("CARGO"));
     cmd.current_dir(cmd_current_dir);
@@ -83,6 +111,19 @@ fn build(project: &str) -> Result<(u64, TempDir)> {
     Ok((new_size, target))
 }
 
+/// Adds the library unit of a crate the projects don't have to `target/debug`, with its fingerprint and rlib.
+/// Returns the fingerprint folder and the rlib.
+fn fake_unit(target: &Path, name: &str, hash: &str) -> Result<(PathBuf, PathBuf)> {
+    let debug = target.join("debug");
+    let crate_name = name.replace('-', "_");
+    let fingerprint = debug.join(format!(".fingerprint/{name}-{hash}"));
+    fs::create_dir_all(&fingerprint)?;
+    fs::write(fingerprint.join(format!("lib-{crate_name}.json")), "{}")?;
+    let rlib = debug.join(format!("deps/lib{crate_name}-{hash}.rlib"));
+    fs::write(&rlib, vec![0; 10_000])?;
+    Ok((fingerprint, rlib))
+}
+
 /// Run the sweep command, cleaning up target files, and then parse the results and
 /// return the number of cleaned files. This function takes a `cmd_modifier` which can
 /// be used to modify the `sweep` [Command], e.g. adding or changing environment variables.
@@ -183,6 +224,7 @@ fn all_flags() -> TestResult {
     let all_combos = [
         ["--time", "0"].as_slice(),
         &["--maxsize", "0"],
+        &["--time", "0", "--maxsize", "0"],
         // TODO(#67): enable this test
         // &["--installed"],
     ];
@@ -247,8 +289,7 @@ fn empty_project_output() -> TestResult {
     // Please note: The output from the sweep command is platform dependent. The regular
     // expression tries to take that into account by letting the file output order vary.
     let pattern = unindent(
-        r#"\[DEBUG\] cleaning: ".+" with remove_older_until_fits
-        \[DEBUG\] size_to_remove: .+
+        r#"\[DEBUG\] planning: ".+" with plan_sweep
         \[DEBUG\] Sizing: ".+debug" with total_disk_space_in_a_profile
         \[DEBUG\] Hashs by time: \[
             \(
@@ -256,7 +297,8 @@ fn empty_project_output() -> TestResult {
                 ".+",
             \),
         \]
-        \[DEBUG\] cleaning: ".+debug" with remove_not_built_with_in_a_profile
+        \[DEBUG\] size_to_remove: .+
+        \[DEBUG\] planning: ".+debug" with plan_not_built_with_in_a_profile
         \[DEBUG\] Successfully removed: ".+sample_project.+"
         (\s*\S*)*
         \[INFO\] Cleaned .+ from ".+""#,
@@ -310,9 +352,11 @@ fn error_output() -> TestResult {
     }
 
     let (_, tempdir) = build("sample-project")?;
+    let cargo_home = tempfile::tempdir()?;
     let assert = run(sweep(&["--installed"])
         .env("PATH", test_dir())
-        .env("CARGO_TARGET_DIR", tempdir.path()));
+        .env("CARGO_TARGET_DIR", tempdir.path())
+        .env("CARGO_HOME", cargo_home.path()));
     assert.stdout(contains("oh no an error"));
 
     Ok(())
@@ -349,9 +393,11 @@ fn path() -> TestResult {
     cmd.arg("sweep").arg("--installed").current_dir(temp_dir());
 
     // Pass `path` as an argument, instead of `current_dir` like it normally is.
+    let cargo_home = tempdir()?;
     let assert = run(cmd
         .arg(project_dir("sample-project"))
-        .env("CARGO_TARGET_DIR", target.path()));
+        .env("CARGO_TARGET_DIR", target.path())
+        .env("CARGO_HOME", cargo_home.path()));
     assert.stdout(contains("Cleaned"));
 
     Ok(())
@@ -597,9 +643,11 @@ fn multiple_paths_and_stamp_errors() -> TestResult {
 #[test]
 fn check_toolchain_listing_on_multiple_projects() -> TestResult {
     let args = &["sweep", "--dry-run", "--recursive", "--installed"];
+    let cargo_home = tempdir()?;
     let assert = run(Command::new(cargo_bin("cargo-sweep"))
         .args(args)
-        .current_dir("tests/"));
+        .current_dir("tests/")
+        .env("CARGO_HOME", cargo_home.path()));
 
     let stdout = std::str::from_utf8(&assert.get_output().stdout).unwrap();
     let lines = stdout
@@ -607,7 +655,7 @@ fn check_toolchain_listing_on_multiple_projects() -> TestResult {
         .filter(|line| line.starts_with("[INFO]"))
         .collect::<Vec<_>>();
 
-    assert_eq!(lines.len(), 4);
+    assert_eq!(lines.len(), 5);
     assert_eq!(
         lines[0].trim(),
         "[INFO] Searching recursively for Rust project folders"
@@ -615,6 +663,992 @@ fn check_toolchain_listing_on_multiple_projects() -> TestResult {
     assert!(lines[1].starts_with("[INFO] Using all installed toolchains:"));
     assert!(lines[2].starts_with("[INFO] Would clean:"));
     assert!(lines[3].starts_with("[INFO] Would clean:"));
+    assert!(lines[4].starts_with("[INFO] Total amount:"));
+
+    Ok(())
+}
+
+#[test]
+fn library_lists_units() -> TestResult {
+    let (_, target) = build("sample-project")?;
+
+    let target_dir = cargo_sweep::TargetDir::new(target.path());
+    let profiles = target_dir.profiles().collect::<Vec<_>>();
+    assert_eq!(profiles.len(), 1);
+    assert_eq!(profiles[0].name(), "debug");
+
+    let units = profiles[0].units()?;
+    let unit = units
+        .iter()
+        .find(|unit| unit.name == "sample-project")
+        .context("missing sample_project unit")?;
+    assert_eq!(unit.hash.len(), 16);
+    assert!(unit.rustc.is_some());
+    assert!(unit.size > 0);
+
+    Ok(())
+}
+
+#[test]
+fn keep_latest() -> TestResult {
+    let (_, target) = build("sample-project")?;
+    // Another build of the same library, with other settings.
+    run(cargo(project_dir("sample-project"))
+        .arg("build")
+        .env("CARGO_TARGET_DIR", target.path())
+        .env("CARGO_PROFILE_DEV_OPT_LEVEL", "1"));
+    let lib_builds = || -> Result<Vec<PathBuf>> {
+        let mut builds = vec![];
+        for entry in fs::read_dir(target.path().join("debug/.fingerprint"))? {
+            let path = entry?.path();
+            if path.join("lib-sample_project.json").is_file() {
+                builds.push(path);
+            }
+        }
+        Ok(builds)
+    };
+    let builds = lib_builds()?;
+    assert_eq!(builds.len(), 2);
+    let latest = builds
+        .iter()
+        .max_by_key(|path| {
+            fs::metadata(path.join("lib-sample_project.json"))
+                .unwrap()
+                .modified()
+                .unwrap()
+        })
+        .unwrap()
+        .clone();
+
+    run(sweep(&["--keep-latest", "1"]).env("CARGO_TARGET_DIR", target.path()));
+    assert_eq!(lib_builds()?, [latest]);
+    assert!(target.path().join("debug/libsample_project.rlib").is_file());
+
+    // Nothing is left to remove.
+    let target_dir = cargo_sweep::TargetDir::new(target.path());
+    let criteria = [cargo_sweep::Criterion::KeepLatest(1)];
+    assert!(cargo_sweep::plan_sweep(&target_dir, &criteria)?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn unreferenced() -> TestResult {
+    let (_, target) = build("sample-project")?;
+    // A dependency that was removed from the project since it was built.
+    let debug = target.path().join("debug");
+    let (fingerprint, rlib) = fake_unit(target.path(), "old-dependency", "0123456789abcdef")?;
+
+    run(sweep(&["--unreferenced"]).env("CARGO_TARGET_DIR", target.path()));
+    assert!(!fingerprint.exists());
+    assert!(!rlib.exists());
+    assert!(debug.join("libsample_project.rlib").is_file());
+
+    Ok(())
+}
+
+#[test]
+fn shared_target_dir() -> TestResult {
+    let projects_dir = tempdir()?;
+    let target = tempdir()?;
+    let mut rlibs = vec![];
+    for name in ["alpha", "beta"] {
+        fs_extra::dir::copy(
+            project_dir("sample-project"),
+            projects_dir.path(),
+            &CopyOptions::default(),
+        )?;
+        let project_path = projects_dir.path().join(name);
+        fs::rename(projects_dir.path().join("sample-project"), &project_path)?;
+        let manifest = project_path.join("Cargo.toml");
+        fs::write(
+            &manifest,
+            fs::read_to_string(&manifest)?.replace("sample-project", name),
+        )?;
+        run(cargo(&project_path)
+            .args(["build", "--lib"])
+            .env("CARGO_TARGET_DIR", target.path()));
+        rlibs.push(target.path().join(format!("debug/lib{name}.rlib")));
+    }
+    let sweep_all = |args: &[&str]| {
+        run(sweep(args)
+            .arg("--recursive")
+            .current_dir(projects_dir.path())
+            .env("CARGO_TARGET_DIR", target.path()));
+    };
+
+    // Every workspace using the target folder is taken into account, not only the first one found.
+    sweep_all(&["--unreferenced"]);
+    assert!(rlibs.iter().all(|rlib| rlib.is_file()));
+    sweep_all(&["--time", "0", "--only-deps"]);
+    assert!(rlibs.iter().all(|rlib| rlib.is_file()));
+    sweep_all(&["--time", "0", "--only-members"]);
+    assert!(rlibs.iter().all(|rlib| !rlib.exists()));
+
+    Ok(())
+}
+
+#[test]
+fn only_members_and_deps() -> TestResult {
+    let (_, target) = build("sample-project")?;
+    // A dependency, which the sample project doesn't have.
+    let debug = target.path().join("debug");
+    let (fingerprint, rlib) = fake_unit(target.path(), "dependency", "0123456789abcdef")?;
+
+    run(sweep(&["--time", "0", "--only-deps"]).env("CARGO_TARGET_DIR", target.path()));
+    assert!(!fingerprint.exists());
+    assert!(!rlib.exists());
+    assert!(debug.join("libsample_project.rlib").is_file());
+
+    run(sweep(&["--time", "0", "--only-members"]).env("CARGO_TARGET_DIR", target.path()));
+    assert!(!debug.join("libsample_project.rlib").exists());
+
+    Ok(())
+}
+
+#[test]
+fn ci_prepare() -> TestResult {
+    let _lock = CONFLICTING_TESTS_MUTEX.lock();
+
+    let (_, target) = build("sample-project")?;
+    let debug = target.path().join("debug");
+    let pause = || std::thread::sleep(std::time::Duration::from_millis(100));
+
+    // Restored from the cache, but not used by the job.
+    let (unused, _) = fake_unit(target.path(), "dependency", "0000000000000000")?;
+    pause();
+    run(sweep(&["--stamp"]).env("CARGO_TARGET_DIR", target.path()));
+    pause();
+    let (used, rlib) = fake_unit(target.path(), "dependency", "1111111111111111")?;
+    // The unit testing the library.
+    let (test, _) = fake_unit(target.path(), "dependency", "2222222222222222")?;
+    fs::rename(
+        test.join("lib-dependency.json"),
+        test.join("test-lib-dependency.json"),
+    )?;
+
+    run(sweep(&["ci-prepare"]).env("CARGO_TARGET_DIR", target.path()));
+    assert!(!debug.join("libsample_project.rlib").exists());
+    assert!(!unused.exists());
+    assert!(!test.exists());
+    assert!(used.exists());
+    assert!(rlib.is_file());
+    assert!(!project_dir("sample-project")
+        .join("sweep.timestamp")
+        .exists());
+
+    // Without a stamp, there is no telling what the job used.
+    sweep(&["ci-prepare"])
+        .env("CARGO_TARGET_DIR", target.path())
+        .assert()
+        .failure()
+        .stderr(contains("cargo sweep --stamp"));
+
+    Ok(())
+}
+
+#[test]
+fn exec() -> TestResult {
+    let (_, target) = build("sample-project")?;
+    // A unit the command doesn't use.
+    let debug = target.path().join("debug");
+    let (fingerprint, _) = fake_unit(target.path(), "dependency", "0123456789abcdef")?;
+    std::thread::sleep(std::time::Duration::from_millis(100));
+
+    let cargo = env!("CARGO");
+    // Nothing is swept when the command fails, and its exit code is passed on.
+    sweep(&["exec", "--", cargo, "no-such-command"])
+        .env("CARGO_TARGET_DIR", target.path())
+        .assert()
+        .code(101)
+        .stdout(contains("Skipping the sweep"));
+    assert!(fingerprint.exists());
+    assert!(debug.join("libsample_project.rlib").is_file());
+
+    run(sweep(&["exec", "--", cargo, "build"]).env("CARGO_TARGET_DIR", target.path()))
+        .stdout(contains("Cleaned"));
+    assert!(!fingerprint.exists());
+    assert!(debug.join("libsample_project.rlib").is_file());
+    // The current incremental session is kept along with its crate, rustc doesn't touch it without changes.
+    let mut sessions = fs::read_dir(debug.join("incremental"))?;
+    let crate_dir = sessions
+        .next()
+        .context("missing incremental session")??
+        .path();
+    assert!(fs::read_dir(crate_dir)?.any(|session| session.is_ok_and(|s| s.path().is_dir())));
+
+    // A dry run given before the subcommand is honoured, even though the command uses nothing.
+    run(sweep(&["--dry-run", "exec", "--", cargo, "--version"])
+        .env("CARGO_TARGET_DIR", target.path()))
+    .stdout(contains("Would clean: ").and(contains("Would clean: nothing").not()));
+    assert!(debug.join("libsample_project.rlib").is_file());
+
+    // A failed command is swept when asked to, which removes everything as it used nothing.
+    sweep(&["exec", "--sweep-on-failure", "--", cargo, "no-such-command"])
+        .env("CARGO_TARGET_DIR", target.path())
+        .assert()
+        .code(101)
+        .stdout(contains("Cleaned"));
+    assert!(!debug.join("libsample_project.rlib").exists());
+
+    Ok(())
+}
+
+#[test]
+fn rustc_wrapper_usage_log() -> TestResult {
+    let target = tempdir()?;
+    run(cargo(project_dir("sample-project"))
+        .arg("build")
+        .env("CARGO_TARGET_DIR", target.path())
+        .env("RUSTC_WRAPPER", cargo_bin("cargo-sweep-rustc-wrapper")));
+    let debug = target.path().join("debug");
+    let log = fs::read_to_string(debug.join(cargo_sweep::USAGE_LOG_FILE))?;
+    assert!(log.contains("\tsample_project\t"), "{log}");
+
+    // Access times that weren't updated since long ago, like with `noatime`.
+    let long_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(30 * 24 * 3600);
+    for entry in fs::read_dir(debug.join(".fingerprint"))? {
+        for file in fs::read_dir(entry?.path())? {
+            fs::File::options()
+                .write(true)
+                .open(file?.path())?
+                .set_times(fs::FileTimes::new().set_accessed(long_ago))?;
+        }
+    }
+    let target_dir = cargo_sweep::TargetDir::new(target.path());
+    let criteria = [cargo_sweep::Criterion::OlderThan(
+        std::time::Duration::from_secs(7 * 24 * 3600),
+    )];
+    assert!(cargo_sweep::plan_sweep(&target_dir, &criteria)?.is_empty());
+
+    fs::remove_file(debug.join(cargo_sweep::USAGE_LOG_FILE))?;
+    assert!(!cargo_sweep::plan_sweep(&target_dir, &criteria)?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn library_plans_selected_units() -> TestResult {
+    let (size, target) = build("sample-project")?;
+    let target_dir = cargo_sweep::TargetDir::new(target.path());
+    let profile = target_dir.profiles().next().context("missing profile")?;
+    let units = profile.units()?;
+    let unit = units
+        .iter()
+        .find(|unit| unit.name == "sample-project")
+        .context("missing sample_project unit")?;
+
+    let hashes = [unit.hash.clone()].into_iter().collect();
+    let plan = cargo_sweep::plan_selected(&target_dir, &hashes)?;
+    assert!(!plan.is_empty());
+    assert!(plan.entries().iter().all(|entry| entry.hash == unit.hash));
+    assert_eq!(plan.total_size(), unit.size);
+
+    plan.execute();
+    assert!(!target.path().join("debug/libsample_project.rlib").exists());
+    assert!(get_size(target.path())? < size);
+    assert!(cargo_sweep::plan_selected(&target_dir, &hashes)?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn plan_of_copied_uplifts_is_not_stale() -> TestResult {
+    let (_, target) = build("sample-project")?;
+    let debug = target.path().join("debug");
+    // Like on filesystems without hard links, where Cargo copies the artifacts it uplifts.
+    let rlib = debug.join("libsample_project.rlib");
+    let hashed = fs::read_dir(debug.join("deps"))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .find(|path| path.extension().is_some_and(|ext| ext == "rlib"))
+        .context("missing rlib in deps")?;
+    fs::remove_file(&rlib)?;
+    fs::copy(&hashed, &rlib)?;
+    // Access times older than the modification times are updated by any read, even with `relatime`.
+    for path in [&rlib, &hashed] {
+        let modified = fs::metadata(path)?.modified()?;
+        let accessed = modified - std::time::Duration::from_secs(3600);
+        fs::File::options()
+            .write(true)
+            .open(path)?
+            .set_times(fs::FileTimes::new().set_accessed(accessed))?;
+    }
+
+    let target_dir = cargo_sweep::TargetDir::new(target.path());
+    let mut plan = cargo_sweep::plan_sweep(
+        &target_dir,
+        &[cargo_sweep::Criterion::OlderThan(std::time::Duration::ZERO)],
+    )?;
+    let planned = plan.entries().len();
+    assert!(plan.entries().iter().any(|entry| entry.path == rlib));
+    // Matching the copy with its unit doesn't make the plan look used since.
+    plan.skip_stale();
+    assert_eq!(plan.entries().len(), planned);
+
+    Ok(())
+}
+
+#[test]
+fn report() -> TestResult {
+    let (size, target) = build("sample-project")?;
+    // Keeps the toolchains seen by the report out of the real `~/.cargo`.
+    let cargo_home = tempdir()?;
+    let env = [
+        ("CARGO_TARGET_DIR", target.path()),
+        ("CARGO_HOME", cargo_home.path()),
+    ];
+
+    run(sweep(&["report"]).envs(env)).stdout(contains("Crate").and(contains("sample-project")));
+
+    let assert = run(sweep(&["report", "--message-format", "json"]).envs(env));
+    let report: serde_json::Value = serde_json::from_slice(&assert.get_output().stdout)?;
+    assert_eq!(report["type"], "report");
+    let total = |usages: &serde_json::Value| -> u64 {
+        usages
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|usage| usage["size"].as_u64().unwrap())
+            .sum()
+    };
+    let units_size = report["units_size"].as_u64().unwrap();
+    assert!(units_size > 0);
+    for breakdown in [
+        "by_profile",
+        "by_target",
+        "by_crate",
+        "by_toolchain",
+        "by_age",
+    ] {
+        assert_eq!(total(&report[breakdown]), units_size, "{breakdown}");
+    }
+    assert_eq!(
+        units_size + total(&report["untracked"]),
+        report["size"].as_u64().unwrap()
+    );
+
+    // Reporting doesn't remove anything.
+    assert_eq!(get_size(target.path())?, size);
+
+    Ok(())
+}
+
+#[test]
+fn report_remembers_toolchains() -> TestResult {
+    let (_, target) = build("sample-project")?;
+    let cargo_home = tempdir()?;
+    let stored = cargo_home.path().join("cargo-sweep/toolchains.json");
+
+    let assert = run(sweep(&["report", "--message-format", "json"])
+        .env("CARGO_TARGET_DIR", target.path())
+        .env("CARGO_HOME", cargo_home.path()));
+    let report: serde_json::Value = serde_json::from_slice(&assert.get_output().stdout)?;
+
+    // Every unit was built by a known toolchain, which is remembered for when it is uninstalled.
+    let hash = Regex::new("^[0-9a-f]{16}$")?;
+    let toolchains = report["by_toolchain"].as_array().unwrap();
+    assert!(!toolchains.is_empty());
+    assert!(toolchains
+        .iter()
+        .all(|toolchain| !hash.is_match(toolchain["name"].as_str().unwrap())));
+    let stored: serde_json::Value = serde_json::from_str(&fs::read_to_string(stored)?)?;
+    assert!(stored["versions"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|version| version.as_str().unwrap().starts_with("rustc ")));
+
+    Ok(())
+}
+
+#[test]
+fn sweep_by_toolchain() -> TestResult {
+    let (_, target) = build("sample-project")?;
+    let cargo_home = tempdir()?;
+    // A unit built by a toolchain that isn't known, as it was neither installed nor seen before.
+    let (fingerprint, _) = fake_unit(target.path(), "dependency", "0123456789abcdef")?;
+    fs::write(fingerprint.join("lib-dependency.json"), r#"{"rustc":1234}"#)?;
+
+    run(sweep(&["--installed"])
+        .env("CARGO_TARGET_DIR", target.path())
+        .env("CARGO_HOME", cargo_home.path()))
+    .stdout(contains("built by 00000000000004d2"));
+    assert!(!fingerprint.exists());
+    assert!(target.path().join("debug/libsample_project.rlib").is_file());
+
+    Ok(())
+}
+
+#[test]
+fn plan_out_and_apply() -> TestResult {
+    let (size, target) = build("sample-project")?;
+    let plan_dir = tempdir()?;
+    let plan_file = plan_dir.path().join("plan.json");
+    let plan_arg = plan_file.to_str().unwrap();
+
+    // Writing the plan must not remove anything.
+    run(sweep(&["--time", "0", "--plan-out", plan_arg]).env("CARGO_TARGET_DIR", target.path()))
+        .stdout(contains("Would clean: ").and(contains("Wrote plan to")));
+    assert_eq!(get_size(target.path())?, size);
+
+    let plan = cargo_sweep::SweepPlan::load(&plan_file)?;
+    assert!(plan.total_size() > 0);
+    assert!(plan
+        .entries()
+        .iter()
+        .all(|entry| entry.path.starts_with(target.path())));
+
+    // A dry run given before the subcommand is honoured, and the target folder is reported.
+    run(sweep(&["--dry-run", "apply", plan_arg]).env("CARGO_TARGET_DIR", target.path()))
+        .stdout(contains(format!("from {:?}", target.path())).and(contains(plan_arg).not()));
+    assert_eq!(get_size(target.path())?, size);
+
+    let args = &["apply", plan_arg];
+    let expected_cleaned = count_cleaned_dry_run(&target, args, size)?;
+    assert!(expected_cleaned > 0);
+
+    let actual_cleaned = count_cleaned(&target, args, size)?;
+    assert_eq!(actual_cleaned, expected_cleaned);
+
+    Ok(())
+}
+
+#[test]
+fn json_output() -> TestResult {
+    let (size, target) = build("sample-project")?;
+
+    let assert =
+        run(sweep(&["--time", "0", "--message-format", "json"])
+            .env("CARGO_TARGET_DIR", target.path()));
+    let output = assert.get_output();
+    assert!(std::str::from_utf8(&output.stderr)?.contains("[INFO] Cleaned"));
+
+    let messages = std::str::from_utf8(&output.stdout)?
+        .lines()
+        .map(serde_json::from_str::<serde_json::Value>)
+        .collect::<Result<Vec<_>, _>>()?;
+    let of_type = |ty: &str| {
+        messages
+            .iter()
+            .filter(|message| message["type"] == ty)
+            .collect::<Vec<_>>()
+    };
+
+    let artifacts = of_type("artifact");
+    assert!(!artifacts.is_empty());
+    assert!(artifacts
+        .iter()
+        .all(|artifact| artifact["dry_run"] == false));
+    let artifact_total: u64 = artifacts
+        .iter()
+        .map(|artifact| artifact["size"].as_u64().unwrap())
+        .sum();
+
+    let projects = of_type("project");
+    assert_eq!(projects.len(), 1);
+    assert_eq!(projects[0]["cleaned"].as_u64(), Some(artifact_total));
+    assert_eq!(
+        of_type("total")[0]["cleaned"].as_u64(),
+        Some(artifact_total)
+    );
+    assert_eq!(messages.last(), of_type("total").first().copied());
+
+    assert_sweeped_size(target.path(), artifact_total, size)?;
+
+    Ok(())
+}
+
+#[test]
+fn config_defaults() -> TestResult {
+    let project_root_path = tempdir()?;
+    fs_extra::dir::copy(
+        project_dir("sample-project"),
+        project_root_path.path(),
+        &CopyOptions::default(),
+    )?;
+    let project_path = project_root_path.path().join("sample-project");
+
+    let manifest_path = project_path.join("Cargo.toml");
+    let mut manifest = fs::read_to_string(&manifest_path)?;
+    manifest.push_str("\n[package.metadata.sweep]\ntime = 0\nexclude = [\"sample-project\"]\n");
+    fs::write(&manifest_path, manifest)?;
+
+    let target = tempdir()?;
+    run(cargo(&project_path)
+        .arg("build")
+        .env("CARGO_TARGET_DIR", target.path()));
+    let size = get_size(target.path())?;
+
+    // The criterion comes from the package metadata, but the only unit is excluded.
+    run(sweep(&[])
+        .current_dir(&project_path)
+        .env("CARGO_TARGET_DIR", target.path()))
+    .stdout(contains("Cleaned nothing"));
+    assert_eq!(get_size(target.path())?, size);
+
+    // `.cargo-sweep.toml` takes precedence over the package metadata.
+    fs::write(project_path.join(".cargo-sweep.toml"), "exclude = []\n")?;
+    let cleaned = clean_and_parse(&[], |cmd| {
+        cmd.current_dir(&project_path)
+            .env("CARGO_TARGET_DIR", target.path())
+    })?;
+    assert!(cleaned > 0);
+    assert_sweeped_size(target.path(), cleaned, size)?;
+
+    Ok(())
+}
+
+#[test]
+fn config_of_commands() -> TestResult {
+    let (_, target) = build("sample-project")?;
+    let dir = tempdir()?;
+    let project_path = dir.path().join("sample-project");
+    fs_extra::dir::copy(
+        project_dir("sample-project"),
+        dir.path(),
+        &CopyOptions::default(),
+    )?;
+    fs::write(
+        project_path.join(".cargo-sweep.toml"),
+        "message-format = \"json\"\n",
+    )?;
+    // A broken configuration where the commands are run.
+    fs::write(dir.path().join(".cargo-sweep.toml"), "no-such-key = 1\n")?;
+    let plan_file = dir.path().join("plan.json");
+    cargo_sweep::SweepPlan::new().store(&plan_file)?;
+
+    // Applying a plan takes no criteria, so it doesn't need a configuration.
+    run(sweep(&["apply", plan_file.to_str().unwrap()]).current_dir(dir.path()));
+
+    // The configuration is taken from the project reported on.
+    let assert = run(sweep(&["report", project_path.to_str().unwrap()])
+        .current_dir(dir.path())
+        .env("CARGO_TARGET_DIR", target.path()));
+    let report: serde_json::Value = serde_json::from_slice(&assert.get_output().stdout)?;
+    assert_eq!(report["type"], "report");
+
+    Ok(())
+}
+
+#[test]
+fn combined_criteria() -> TestResult {
+    let (_, target) = build("sample-project")?;
+
+    let reasons = |args: &[&str]| -> Result<Vec<serde_json::Value>> {
+        let mut args = args.to_vec();
+        args.extend(["--dry-run", "--message-format", "json"]);
+        let assert = run(sweep(&args).env("CARGO_TARGET_DIR", target.path()));
+        std::str::from_utf8(&assert.get_output().stdout)?
+            .lines()
+            .map(serde_json::from_str::<serde_json::Value>)
+            .filter(|message| {
+                message
+                    .as_ref()
+                    .map_or(true, |message| message["type"] == "artifact")
+            })
+            .map(|message| Ok(message?["reason"].clone()))
+            .collect()
+    };
+
+    // Nothing is older than a day, so everything is removed to fit the size.
+    let reasons_by_size = reasons(&["--time", "1", "--maxsize", "0"])?;
+    assert!(!reasons_by_size.is_empty());
+    assert!(reasons_by_size.iter().all(|reason| reason["max-size"] == 0));
+
+    // The age is checked first, leaving nothing for the size to remove.
+    let reasons_by_age = reasons(&["--maxsize", "0", "--time", "0"])?;
+    assert_eq!(reasons_by_age.len(), reasons_by_size.len());
+    assert!(reasons_by_age
+        .iter()
+        .all(|reason| reason.get("older-than").is_some()));
+
+    Ok(())
+}
+
+#[test]
+fn total_maxsize() -> TestResult {
+    let projects_dir = tempdir()?;
+    let mut targets = vec![];
+    for name in ["a", "b"] {
+        let dir = projects_dir.path().join(name);
+        fs::create_dir(&dir)?;
+        fs_extra::dir::copy(project_dir("sample-project"), &dir, &CopyOptions::default())?;
+        let project_path = dir.join("sample-project");
+        run(cargo(&project_path)
+            .env_remove("CARGO_TARGET_DIR")
+            .arg("build"));
+        targets.push(project_path.join("target"));
+    }
+    let combined_size = |targets: &[PathBuf]| -> Result<u64> { targets.iter().map(get_size).sum() };
+    let old_size = combined_size(&targets)?;
+
+    // Each target folder fits on its own, but not together with the other one.
+    let budget = format!("{}B", old_size * 3 / 4);
+    run(sweep(&["-r", "--dry-run", "--maxsize", &budget])
+        .env_remove("CARGO_TARGET_DIR")
+        .current_dir(projects_dir.path()))
+    .stdout(contains("Would remove:").not());
+
+    run(sweep(&["-r", "--total-maxsize", &budget])
+        .env_remove("CARGO_TARGET_DIR")
+        .current_dir(projects_dir.path()))
+    .stdout(contains("Successfully removed"));
+    assert!(combined_size(&targets)? < old_size);
+
+    Ok(())
+}
+
+#[test]
+fn ensure_free() -> TestResult {
+    let (size, target) = build("sample-project")?;
+
+    // There is always some free space already.
+    run(sweep(&["--ensure-free", "0"]).env("CARGO_TARGET_DIR", target.path()))
+        .stdout(contains("Cleaned nothing"));
+    assert_eq!(get_size(target.path())?, size);
+
+    // The filesystem can't be entirely free, so everything is removed trying.
+    let everything = count_cleaned_dry_run(&target, &["--all"], size)?;
+    let cleaned = count_cleaned(&target, &["--ensure-free", "100%"], size)?;
+    assert_eq!(cleaned, everything);
+
+    Ok(())
+}
+
+#[test]
+fn incremental_sessions() -> TestResult {
+    let project_root_path = tempdir()?;
+    fs_extra::dir::copy(
+        project_dir("sample-project"),
+        project_root_path.path(),
+        &CopyOptions::default(),
+    )?;
+    let project_path = project_root_path.path().join("sample-project");
+    let target = tempdir()?;
+    let build = || {
+        run(cargo(&project_path)
+            .arg("build")
+            .env("CARGO_TARGET_DIR", target.path()));
+    };
+
+    // A change makes rustc start a new session, without removing the old one right away.
+    build();
+    let lib = project_path.join("src/lib.rs");
+    fs::write(&lib, fs::read_to_string(&lib)? + "\npub fn added() {}\n")?;
+    build();
+    let sessions = |target: &Path| -> Result<usize> {
+        let mut sessions = 0;
+        for crate_dir in fs::read_dir(target.join("debug/incremental"))? {
+            for session in fs::read_dir(crate_dir?.path())? {
+                sessions += session?.path().is_dir() as usize;
+            }
+        }
+        Ok(sessions)
+    };
+    assert_eq!(sessions(target.path())?, 2);
+
+    // Nothing is older than 30 days, but the old session is never used again.
+    let assert = run(sweep(&["--time", "30", "--message-format", "json"])
+        .current_dir(&project_path)
+        .env("CARGO_TARGET_DIR", target.path()));
+    let reasons: Vec<serde_json::Value> = std::str::from_utf8(&assert.get_output().stdout)?
+        .lines()
+        .map(serde_json::from_str::<serde_json::Value>)
+        .filter_map(|message| message.ok())
+        .filter(|message| message["type"] == "artifact")
+        .map(|message| message["reason"].clone())
+        .collect();
+    assert!(!reasons.is_empty());
+    assert!(reasons.iter().all(|reason| reason == "superseded"));
+    assert_eq!(sessions(target.path())?, 1);
+
+    // Everything left is removed by age.
+    run(sweep(&["--time", "0"])
+        .current_dir(&project_path)
+        .env("CARGO_TARGET_DIR", target.path()));
+    assert_eq!(sessions(target.path())?, 0);
+
+    Ok(())
+}
+
+#[test]
+fn examples() -> TestResult {
+    let target = tempdir()?;
+    run(cargo(project_dir("sample-project"))
+        .args(["build", "--examples"])
+        .env("CARGO_TARGET_DIR", target.path()));
+    let size = get_size(target.path())?;
+    let examples_dir = target.path().join("debug/examples");
+    let hashed_examples = || -> Result<Vec<String>> {
+        let mut hashed = vec![];
+        for entry in fs::read_dir(&examples_dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if name.starts_with("add-") {
+                hashed.push(name);
+            }
+        }
+        Ok(hashed)
+    };
+    assert!(!hashed_examples()?.is_empty());
+
+    count_cleaned(&target, &["--time", "0"], size)?;
+    assert_eq!(hashed_examples()?, Vec::<String>::new());
+
+    Ok(())
+}
+
+#[test]
+fn uplifted() -> TestResult {
+    let target = tempdir()?;
+    run(cargo(project_dir("sample-project"))
+        .args(["build", "--lib", "--examples"])
+        .env("CARGO_TARGET_DIR", target.path()));
+    let size = get_size(target.path())?;
+    let debug = target.path().join("debug");
+    let rlib = debug.join("libsample_project.rlib");
+    let dep_info = debug.join("libsample_project.d");
+    assert!(rlib.is_file());
+    assert!(dep_info.is_file());
+
+    // Kept along with the unit they were uplifted from.
+    run(sweep(&["--time", "30"]).env("CARGO_TARGET_DIR", target.path()));
+    assert!(rlib.is_file());
+    assert!(dep_info.is_file());
+
+    count_cleaned(&target, &["--time", "0"], size)?;
+    assert!(!rlib.exists());
+    assert!(!dep_info.exists());
+    assert!(!debug.join("examples/add").exists());
+
+    Ok(())
+}
+
+#[test]
+fn uplifted_cdylib() -> TestResult {
+    let (_, target) = build("cdylib-project")?;
+    // Cargo doesn't name these after the hash of their unit, in `deps` either.
+    let mut uplifted = vec![];
+    for entry in fs::read_dir(target.path().join("debug"))? {
+        let path = entry?.path();
+        if path.is_file() && path.file_name().unwrap() != ".cargo-lock" {
+            uplifted.push(path);
+        }
+    }
+    assert!(uplifted
+        .iter()
+        .any(|path| path.file_name().unwrap() == "libcdylib_project.rlib"));
+    assert!(uplifted.iter().any(|path| path.extension().unwrap() == "d"));
+
+    // There's no telling which unit they belong to, so they are kept rather than removed as untracked.
+    for args in [["--time", "30"], ["--time", "0"]] {
+        run(sweep(&args).env("CARGO_TARGET_DIR", target.path()));
+        assert!(uplifted.iter().all(|path| path.is_file()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn build_dir() -> TestResult {
+    let target = tempdir()?;
+    let build_dir = tempdir()?;
+    let env = [
+        ("CARGO_TARGET_DIR", target.path()),
+        ("CARGO_BUILD_BUILD_DIR", build_dir.path()),
+    ];
+    run(cargo(project_dir("sample-project"))
+        .args(["build", "--lib", "--examples"])
+        .envs(env));
+    assert!(build_dir.path().join("debug/.fingerprint").is_dir());
+    let rlib = target.path().join("debug/libsample_project.rlib");
+    let example = target.path().join("debug/examples/add");
+    assert!(rlib.is_file());
+    assert!(example.is_file());
+    let size = get_size(build_dir.path())?;
+
+    // Kept along with the units in the build directory.
+    run(sweep(&["--time", "30"]).envs(env));
+    assert!(rlib.is_file());
+    assert!(example.is_file());
+    assert_eq!(get_size(build_dir.path())?, size);
+
+    run(sweep(&["--time", "0"]).envs(env));
+    assert!(!rlib.exists());
+    assert!(!example.exists());
+    // Only the files cargo keeps for the whole build directory are left.
+    assert!(get_size(build_dir.path())? < size / 10);
+
+    Ok(())
+}
+
+#[test]
+fn build_dir_per_package_layout() -> TestResult {
+    let target = tempdir()?;
+    let build_dir = tempdir()?;
+    let env = [
+        ("CARGO_TARGET_DIR", target.path()),
+        ("CARGO_BUILD_BUILD_DIR", build_dir.path()),
+    ];
+    // Cargo keeps every unit in `build/{package}/{hash}`, and uplifts the final artifacts from its `out` folder.
+    let unit = build_dir
+        .path()
+        .join("debug/build/dependency/0123456789abcdef");
+    fs::create_dir_all(unit.join("fingerprint"))?;
+    fs::write(unit.join("fingerprint/lib-dependency.json"), "{}")?;
+    fs::create_dir_all(unit.join("out"))?;
+    let out = unit.join("out/libdependency-0123456789abcdef.rlib");
+    fs::write(&out, vec![0; 10_000])?;
+    fs::create_dir_all(target.path().join("debug"))?;
+    let rlib = target.path().join("debug/libdependency.rlib");
+    fs::hard_link(&out, &rlib)?;
+
+    run(sweep(&["--time", "30"]).envs(env));
+    assert!(unit.is_dir());
+    assert!(rlib.is_file());
+
+    run(sweep(&["--time", "0"]).envs(env));
+    assert!(!unit.exists());
+    assert!(!rlib.exists());
+
+    Ok(())
+}
+
+#[test]
+fn build_dir_root() -> TestResult {
+    let projects_dir = tempdir()?;
+    let root = tempdir()?;
+    let build_dir = format!("{}/{{workspace-path-hash}}", root.path().display());
+    let mut build_dirs = vec![];
+    for name in ["a", "b"] {
+        let dir = projects_dir.path().join(name);
+        fs::create_dir(&dir)?;
+        fs_extra::dir::copy(project_dir("sample-project"), &dir, &CopyOptions::default())?;
+        let project_path = dir.join("sample-project");
+        run(cargo(&project_path)
+            .arg("build")
+            .env_remove("CARGO_TARGET_DIR")
+            .env("CARGO_BUILD_BUILD_DIR", &build_dir));
+        let hash = cargo_sweep::workspace_path_hash(&fs::canonicalize(&project_path)?);
+        let workspace_build_dir = root.path().join(&hash[..2]).join(&hash[2..]);
+        assert!(workspace_build_dir.is_dir());
+        build_dirs.push(workspace_build_dir);
+    }
+    let [a, b] = &build_dirs[..] else {
+        unreachable!()
+    };
+    let root_arg = root.path().to_str().unwrap();
+
+    // Without searching for every workspace, there is no telling that a workspace is gone.
+    fs::remove_dir_all(projects_dir.path().join("b"))?;
+    run(sweep(&["--build-dir-root", root_arg]).current_dir(projects_dir.path()))
+        .stdout(contains("Cleaned nothing"));
+    assert!(a.exists() && b.exists());
+
+    // Workspaces outside of the current directory would count as gone as well.
+    sweep(&["--build-dir-root", root_arg, "-r"])
+        .current_dir(projects_dir.path())
+        .assert()
+        .failure()
+        .stderr(contains("needs the paths"));
+    assert!(a.exists() && b.exists());
+
+    let projects_arg = projects_dir.path().to_str().unwrap();
+    run(sweep(&["--build-dir-root", root_arg, "-r", projects_arg]));
+    assert!(a.exists());
+    assert!(!b.exists());
+
+    run(sweep(&["--build-dir-root", root_arg, "--time", "0"]).current_dir(projects_dir.path()));
+    assert!(!a.exists());
+
+    Ok(())
+}
+
+#[test]
+fn build_lock() -> TestResult {
+    let (size, target) = build("sample-project")?;
+    let cargo_lock = target
+        .path()
+        .join("debug")
+        .join(cargo_sweep::CARGO_LOCK_FILE);
+    let build = fs::File::open(&cargo_lock)?;
+    build.lock()?;
+
+    // Pretend a build is running.
+    run(sweep(&["--time", "0", "--on-locked", "skip"]).env("CARGO_TARGET_DIR", target.path()))
+        .stdout(contains("Skipping"));
+    assert_eq!(get_size(target.path())?, size);
+
+    // The sweep only starts once the build is done.
+    let release = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        drop(build);
+    });
+    run(sweep(&["--time", "0"]).env("CARGO_TARGET_DIR", target.path()))
+        .stdout(contains("Blocking waiting for file lock"));
+    release.join().unwrap();
+    assert!(get_size(target.path())? < size);
+
+    Ok(())
+}
+
+#[test]
+fn quarantine_restore_and_purge() -> TestResult {
+    let (size, target) = build("sample-project")?;
+    let quarantine = tempdir()?;
+    let quarantine_arg = quarantine.path().to_str().unwrap();
+    let rlib = target.path().join("debug/libsample_project.rlib");
+
+    run(sweep(&["--time", "0", "--quarantine", quarantine_arg])
+        .env("CARGO_TARGET_DIR", target.path()))
+    .stdout(contains("Quarantined").and(contains("cargo sweep restore")));
+    assert!(!rlib.exists());
+    assert!(get_size(target.path())? < size);
+    assert!(get_size(quarantine.path())? > 0);
+
+    // A dry run given before the subcommand is honoured.
+    run(sweep(&[
+        "--dry-run",
+        "restore",
+        "--quarantine",
+        quarantine_arg,
+    ]))
+    .stdout(contains("Would restore: "));
+    assert!(!rlib.exists());
+
+    run(sweep(&["restore", "--quarantine", quarantine_arg]));
+    assert!(rlib.is_file());
+    assert_eq!(get_size(target.path())?, size);
+    assert_eq!(fs::read_dir(quarantine.path())?.count(), 0);
+
+    // Nothing left to restore.
+    sweep(&["restore", "--quarantine", quarantine_arg])
+        .assert()
+        .failure()
+        .stderr(contains("is empty"));
+
+    run(sweep(&["--time", "0", "--quarantine", quarantine_arg])
+        .env("CARGO_TARGET_DIR", target.path()));
+    run(sweep(&[
+        "purge",
+        "--time",
+        "1",
+        "--quarantine",
+        quarantine_arg,
+    ]));
+    assert!(get_size(quarantine.path())? > 0);
+    run(sweep(&[
+        "--dry-run",
+        "purge",
+        "--quarantine",
+        quarantine_arg,
+    ]))
+    .stdout(contains("Would purge: "));
+    assert!(get_size(quarantine.path())? > 0);
+    run(sweep(&["purge", "--quarantine", quarantine_arg]));
+    assert_eq!(get_size(quarantine.path())?, 0);
+    assert!(!rlib.exists());
 
     Ok(())
 }
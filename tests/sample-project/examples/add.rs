@@ -0,0 +1,3 @@
+fn main() {
+    println!("{}", sample_project::add(2, 2));
+}